@@ -0,0 +1,148 @@
+//! Admin endpoints
+//!
+//! Enabled by setting `ADMIN_TOKEN`; requests authenticate with
+//! `Authorization: Bearer <token>`. The token is never accepted in the URL,
+//! and these routes are served without the permissive CORS layer so other
+//! origins cannot call them from a browser.
+//!
+//! - `GET /admin/sessions` lists live sessions
+//! - `GET /admin/sessions/{id}/pipeline` returns the session's pipeline
+//!   graph in DOT format, with negotiated caps and element properties;
+//!   `?format=svg` renders it with Graphviz `dot`
+//! - `GET`/`PUT /admin/redactions` reads or replaces the redacted areas of
+//!   new and all running sessions
+//! - `GET`/`PUT /admin/sessions/{id}/redactions` does the same for one session
+
+use crate::redaction::Redactions;
+use crate::screen_server::AppState;
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+pub fn routes(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/admin/sessions", get(sessions_handler))
+        .route("/admin/sessions/:id/pipeline", get(pipeline_handler))
+        .route("/admin/redactions", get(redactions_handler).put(set_redactions_handler))
+        .route(
+            "/admin/sessions/:id/redactions",
+            get(session_redactions_handler).put(set_session_redactions_handler),
+        )
+        // Runs before the handlers' extractors, so unauthenticated requests
+        // never get to see body parsing errors
+        .route_layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+/// Refuse requests unless admin endpoints are enabled and the token matches
+async fn require_admin(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    if !state.auth.admin_enabled() {
+        return (StatusCode::NOT_FOUND, "Admin endpoints are disabled").into_response();
+    }
+    if !state.auth.authenticate_admin(request.headers()) {
+        return (StatusCode::UNAUTHORIZED, "Admin token required").into_response();
+    }
+    next.run(request).await
+}
+
+async fn sessions_handler(State(state): State<Arc<AppState>>) -> Response {
+    Json(state.sessions.list()).into_response()
+}
+
+async fn pipeline_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let Some(streamer) = state.sessions.streamer(id) else {
+        return (StatusCode::NOT_FOUND, format!("No running session {}", id)).into_response();
+    };
+
+    let dot = streamer.pipeline_dot();
+    match query.get("format").map(String::as_str) {
+        None | Some("dot") => ([(header::CONTENT_TYPE, "text/vnd.graphviz")], dot).into_response(),
+        Some("svg") => match render_svg(&dot).await {
+            Ok(svg) => ([(header::CONTENT_TYPE, "image/svg+xml")], svg).into_response(),
+            Err(e) => (StatusCode::NOT_IMPLEMENTED, format!("Cannot render SVG: {}", e)).into_response(),
+        },
+        Some(other) => (StatusCode::BAD_REQUEST, format!("Unknown format '{}'", other)).into_response(),
+    }
+}
+
+async fn redactions_handler(State(state): State<Arc<AppState>>) -> Response {
+    Json(state.redactions.lock().unwrap().clone()).into_response()
+}
+
+async fn set_redactions_handler(
+    State(state): State<Arc<AppState>>,
+    Json(redactions): Json<Redactions>,
+) -> Response {
+    if let Err(e) = redactions.selectors() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+
+    *state.redactions.lock().unwrap() = redactions.clone();
+    let streamers = state.sessions.streamers();
+    for streamer in &streamers {
+        streamer.set_redactions(redactions.clone());
+    }
+    tracing::info!("Redactions changed for new sessions and {} running", streamers.len());
+    Json(redactions).into_response()
+}
+
+async fn session_redactions_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Response {
+    match state.sessions.streamer(id) {
+        Some(streamer) => Json(streamer.redactions()).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("No running session {}", id)).into_response(),
+    }
+}
+
+async fn set_session_redactions_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Json(redactions): Json<Redactions>,
+) -> Response {
+    if let Err(e) = redactions.selectors() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    let Some(streamer) = state.sessions.streamer(id) else {
+        return (StatusCode::NOT_FOUND, format!("No running session {}", id)).into_response();
+    };
+
+    streamer.set_redactions(redactions.clone());
+    tracing::info!("Redactions changed for session {}", id);
+    Json(redactions).into_response()
+}
+
+/// Render DOT with Graphviz, if installed
+async fn render_svg(dot: &str) -> anyhow::Result<Vec<u8>> {
+    let mut child = tokio::process::Command::new("dot")
+        .arg("-Tsvg")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Graphviz 'dot' not available: {}", e))?;
+
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    stdin.write_all(dot.as_bytes()).await?;
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        anyhow::bail!("dot failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(output.stdout)
+}
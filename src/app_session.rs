@@ -0,0 +1,88 @@
+//! Application streaming sessions
+//!
+//! Each viewer gets a dedicated virtual display running a single configured
+//! application. The session ends when the application exits, and the
+//! application is terminated when the viewer leaves.
+
+use crate::config::env_string;
+use crate::process::{detached, terminate};
+use crate::virtual_display::{VirtualDisplay, VirtualDisplayConfig};
+use anyhow::{Context, Result};
+use std::process::{Child, ExitStatus};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Application mode settings
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    /// Shell command launched on each session's display
+    pub command: String,
+    /// Settings for the per-session virtual display
+    pub display: VirtualDisplayConfig,
+}
+
+impl AppConfig {
+    /// Read `APP_COMMAND`; application mode is off when it is unset
+    pub fn from_env() -> Option<Self> {
+        let command = env_string("APP_COMMAND")?;
+
+        // Every session needs its own display number
+        let display = VirtualDisplayConfig {
+            display: None,
+            ..VirtualDisplayConfig::from_env()
+        };
+
+        Some(Self { command, display })
+    }
+}
+
+/// A running application on its own virtual display
+pub struct AppSession {
+    app: Mutex<Child>,
+    display: VirtualDisplay,
+}
+
+impl AppSession {
+    /// Start a virtual display and launch the application on it
+    pub fn launch(config: &AppConfig) -> Result<Self> {
+        let virtual_display = VirtualDisplay::spawn(&config.display)?;
+
+        let app = detached("sh")
+            .args(["-c", &config.command])
+            .env("DISPLAY", virtual_display.name())
+            .spawn()
+            .with_context(|| format!("Failed to launch application '{}'", config.command))?;
+
+        tracing::info!("Application '{}' launched on {}", config.command, virtual_display.name());
+
+        Ok(Self {
+            app: Mutex::new(app),
+            display: virtual_display,
+        })
+    }
+
+    /// X display the application runs on
+    pub fn display_name(&self) -> &str {
+        self.display.name()
+    }
+
+    /// Resolve once the application has exited
+    pub async fn wait_exit(&self) -> ExitStatus {
+        let mut interval = tokio::time::interval(EXIT_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Ok(Some(status)) = self.app.lock().unwrap().try_wait() {
+                return status;
+            }
+        }
+    }
+}
+
+impl Drop for AppSession {
+    fn drop(&mut self) {
+        terminate(self.app.get_mut().unwrap());
+        tracing::info!("Application on {} terminated", self.display.name());
+    }
+}
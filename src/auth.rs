@@ -0,0 +1,185 @@
+//! User authentication
+//!
+//! Users are configured as `name:password` pairs in `USERS`. Clients
+//! authenticate with HTTP Basic auth. Browsers cannot set headers on
+//! WebSocket connections, so the page trades its Basic credentials for a
+//! short-lived, single-use token (`POST /api/token`) and passes that to `/ws`.
+//!
+//! Admin endpoints use a separate bearer token from `ADMIN_TOKEN`.
+
+use crate::config::env_string;
+use axum::http::{header, HeaderMap};
+use base64::Engine;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a WebSocket token stays valid after being issued
+const TOKEN_TTL: Duration = Duration::from_secs(30);
+
+/// Configured users; authentication is disabled when empty
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    users: HashMap<String, String>,
+    admin_token: Option<String>,
+    /// Outstanding WebSocket tokens: token -> (user, issued at)
+    tokens: Arc<Mutex<HashMap<String, (String, Instant)>>>,
+}
+
+impl Auth {
+    /// Read `USERS` (`alice:secret,bob:hunter2`) from the environment
+    pub fn from_env() -> Self {
+        let users = env_string("USERS")
+            .map(|s| {
+                s.split(',')
+                    .filter_map(|entry| entry.trim().split_once(':'))
+                    .map(|(user, password)| (user.to_string(), password.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            users,
+            admin_token: env_string("ADMIN_TOKEN"),
+            tokens: Default::default(),
+        }
+    }
+
+    /// Whether any users are configured
+    pub fn enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    /// Return the authenticated user name, if the request carries valid Basic credentials
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<String> {
+        let (user, password) = basic_credentials(headers)?;
+        let expected = self.users.get(&user)?;
+        constant_time_eq(expected.as_bytes(), password.as_bytes()).then_some(user)
+    }
+
+    /// Authenticate a WebSocket upgrade by Basic credentials or a `token` query parameter
+    pub fn authenticate_socket(&self, headers: &HeaderMap, query: &HashMap<String, String>) -> Option<String> {
+        self.authenticate(headers)
+            .or_else(|| self.redeem_token(query.get("token")?))
+    }
+
+    /// Issue a single-use WebSocket token for `user`, valid for [`TOKEN_TTL`]
+    pub fn issue_token(&self, user: &str) -> String {
+        let token = hex(&rand::random::<[u8; 32]>());
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, (_, issued)| issued.elapsed() < TOKEN_TTL);
+        tokens.insert(token.clone(), (user.to_string(), Instant::now()));
+        token
+    }
+
+    /// Consume a token, returning its user if it was issued and has not expired
+    fn redeem_token(&self, token: &str) -> Option<String> {
+        let (user, issued) = self.tokens.lock().unwrap().remove(token)?;
+        (issued.elapsed() < TOKEN_TTL).then_some(user)
+    }
+}
+
+impl Auth {
+    /// Whether admin endpoints are enabled
+    pub fn admin_enabled(&self) -> bool {
+        self.admin_token.is_some()
+    }
+
+    /// Whether the request carries the admin token as a bearer token
+    pub fn authenticate_admin(&self, headers: &HeaderMap) -> bool {
+        let Some(expected) = &self.admin_token else { return false };
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim);
+        token.is_some_and(|token| constant_time_eq(expected.as_bytes(), token.as_bytes()))
+    }
+}
+
+/// Extract `user:password` from an `Authorization: Basic` header
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Auth {
+        Auth {
+            users: HashMap::from([("alice".to_string(), "secret".to_string())]),
+            ..Default::default()
+        }
+    }
+
+    fn basic(credentials: &str) -> HeaderMap {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Basic {}", encoded).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn basic_credentials_are_checked() {
+        let auth = auth();
+        assert_eq!(auth.authenticate(&basic("alice:secret")), Some("alice".to_string()));
+        assert_eq!(auth.authenticate(&basic("alice:wrong")), None);
+        assert_eq!(auth.authenticate(&basic("mallory:secret")), None);
+    }
+
+    #[test]
+    fn query_passwords_are_rejected() {
+        let query = HashMap::from([
+            ("user".to_string(), "alice".to_string()),
+            ("password".to_string(), "secret".to_string()),
+        ]);
+        assert_eq!(auth().authenticate_socket(&HeaderMap::new(), &query), None);
+    }
+
+    #[test]
+    fn tokens_are_single_use() {
+        let auth = auth();
+        let query = HashMap::from([("token".to_string(), auth.issue_token("alice"))]);
+        assert_eq!(auth.authenticate_socket(&HeaderMap::new(), &query), Some("alice".to_string()));
+        assert_eq!(auth.authenticate_socket(&HeaderMap::new(), &query), None);
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let auth = auth();
+        let token = auth.issue_token("alice");
+        auth.tokens.lock().unwrap().get_mut(&token).unwrap().1 -= TOKEN_TTL;
+        assert_eq!(auth.redeem_token(&token), None);
+    }
+
+    #[test]
+    fn admin_token_only_as_bearer() {
+        let auth = Auth {
+            admin_token: Some("letmein".to_string()),
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        assert!(!auth.authenticate_admin(&headers));
+        headers.insert(header::AUTHORIZATION, "Bearer letmein".parse().unwrap());
+        assert!(auth.authenticate_admin(&headers));
+        headers.insert(header::AUTHORIZATION, "Bearer nope".parse().unwrap());
+        assert!(!auth.authenticate_admin(&headers));
+    }
+}
@@ -0,0 +1,209 @@
+//! Pipeline bus monitoring
+//!
+//! A running pipeline reports failures only on its bus; unwatched, an
+//! encoder error or a capture source reaching end-of-stream just freezes
+//! the picture. Once started, each streamer's bus is watched: errors and
+//! warnings are logged with their source element, and the first fatal one
+//! is reported so the session can tell the client and, with `AUTO_RESTART`,
+//! rebuild the pipeline after a growing delay.
+//!
+//! `SIMULATE_ERROR_AFTER` inserts an element that fails after a number of
+//! frames, to exercise the whole path.
+
+use futures::StreamExt;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A pipeline that ran this long before failing starts over at the initial delay
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// Why a running pipeline stopped streaming
+#[derive(Debug, Clone)]
+pub struct PipelineFailure {
+    /// Path of the element that reported it
+    pub source: Option<String>,
+    pub reason: String,
+}
+
+impl std::fmt::Display for PipelineFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{} ({})", self.reason, source),
+            None => f.write_str(&self.reason),
+        }
+    }
+}
+
+/// Watch a started pipeline's bus, resolving on the first error or EOS.
+///
+/// Takes over the bus, so call it only after startup checks that pop
+/// messages themselves.
+pub fn watch(pipeline: &gst::Pipeline) -> impl Future<Output = PipelineFailure> + Send + 'static {
+    let bus = pipeline.bus().expect("pipeline without bus");
+    let mut messages = bus.stream();
+
+    async move {
+        while let Some(msg) = messages.next().await {
+            let source = msg.src().map(|s| s.path_string().to_string());
+            match msg.view() {
+                gst::MessageView::Error(err) => {
+                    tracing::error!(
+                        source = source.as_deref().unwrap_or("?"),
+                        error = %err.error(),
+                        debug = ?err.debug(),
+                        "Pipeline error"
+                    );
+                    return PipelineFailure {
+                        source,
+                        reason: err.error().to_string(),
+                    };
+                }
+                gst::MessageView::Warning(warning) => {
+                    tracing::warn!(
+                        source = source.as_deref().unwrap_or("?"),
+                        warning = %warning.error(),
+                        debug = ?warning.debug(),
+                        "Pipeline warning"
+                    );
+                }
+                gst::MessageView::Eos(_) => {
+                    tracing::error!(source = source.as_deref().unwrap_or("?"), "Pipeline reached end of stream");
+                    return PipelineFailure {
+                        source,
+                        reason: "Capture ended".to_string(),
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        PipelineFailure {
+            source: None,
+            reason: "Pipeline bus closed".to_string(),
+        }
+    }
+}
+
+/// Delays between pipeline restarts, doubling while failures keep coming
+#[derive(Debug)]
+pub struct RestartBackoff {
+    delay: Duration,
+    started: Instant,
+}
+
+impl Default for RestartBackoff {
+    fn default() -> Self {
+        Self {
+            delay: INITIAL_BACKOFF,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl RestartBackoff {
+    /// Delay before restarting a pipeline that just failed
+    pub fn next_delay(&mut self) -> Duration {
+        if self.started.elapsed() >= STABLE_AFTER {
+            self.delay = INITIAL_BACKOFF;
+        }
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_BACKOFF);
+        delay
+    }
+
+    /// Note that a rebuilt pipeline is running again
+    pub fn restarted(&mut self) {
+        self.started = Instant::now();
+    }
+}
+
+/// `identity` element that fails after `frames` buffers, if configured
+pub fn create_fault_injector(frames: Option<u32>) -> Option<gst::Element> {
+    let frames = frames?;
+    match gst::ElementFactory::make("identity")
+        .name("fault-injector")
+        .property("error-after", frames as i32)
+        .build()
+    {
+        Ok(identity) => {
+            tracing::warn!("Simulating a pipeline error after {} frames", frames);
+            Some(identity)
+        }
+        Err(e) => {
+            tracing::warn!("Error simulation unavailable: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `videotestsrc [num-buffers] ! [middle] ! fakesink`, playing
+    fn start(num_buffers: Option<i32>, middle: Option<gst::Element>) -> gst::Pipeline {
+        gst::init().unwrap();
+        let pipeline = gst::Pipeline::new();
+        let mut src = gst::ElementFactory::make("videotestsrc");
+        if let Some(n) = num_buffers {
+            src = src.property("num-buffers", n);
+        }
+        let mut chain = vec![src.build().unwrap()];
+        chain.extend(middle);
+        chain.push(gst::ElementFactory::make("fakesink").build().unwrap());
+        pipeline.add_many(&chain).unwrap();
+        gst::Element::link_many(&chain).unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        pipeline
+    }
+
+    async fn failure(pipeline: &gst::Pipeline) -> PipelineFailure {
+        let failure = tokio::time::timeout(Duration::from_secs(10), watch(pipeline))
+            .await
+            .expect("pipeline kept running");
+        pipeline.set_state(gst::State::Null).unwrap();
+        failure
+    }
+
+    #[tokio::test]
+    async fn element_error_is_reported_with_its_source() {
+        let pipeline = start(None, create_fault_injector(Some(5)));
+        let failure = failure(&pipeline).await;
+        assert!(failure.source.as_deref().is_some_and(|s| s.ends_with("fault-injector")));
+        assert!(!failure.reason.is_empty());
+        assert_ne!(failure.reason, "Capture ended");
+    }
+
+    #[tokio::test]
+    async fn end_of_stream_is_a_failure() {
+        let pipeline = start(Some(3), None);
+        let failure = failure(&pipeline).await;
+        assert_eq!(failure.reason, "Capture ended");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = RestartBackoff::default();
+        let delays: Vec<u64> = (0..7).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+    }
+
+    #[test]
+    fn backoff_resets_after_a_stable_run() {
+        let mut backoff = RestartBackoff::default();
+        for _ in 0..4 {
+            backoff.next_delay();
+        }
+        backoff.restarted();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(16));
+
+        backoff.started = Instant::now() - STABLE_AFTER;
+        assert_eq!(backoff.next_delay(), INITIAL_BACKOFF);
+        backoff.restarted();
+        assert_eq!(backoff.next_delay(), INITIAL_BACKOFF * 2);
+    }
+}
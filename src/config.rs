@@ -0,0 +1,38 @@
+//! Environment-based configuration helpers
+//!
+//! Streamio is configured entirely through environment variables. These
+//! helpers keep the parsing consistent across modules.
+
+use std::str::FromStr;
+
+/// Read and parse an environment variable, ignoring unset or invalid values
+pub fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|s| s.trim().parse().ok())
+}
+
+/// Read a non-empty string environment variable
+pub fn env_string(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// Read a boolean flag (`1`, `true`, `yes`, `on` enable it)
+pub fn env_flag(name: &str) -> bool {
+    matches!(
+        std::env::var(name).unwrap_or_default().trim().to_ascii_lowercase().as_str(),
+        "1" | "true" | "yes" | "on"
+    )
+}
+
+/// Parse a `WIDTHxHEIGHT` resolution string
+pub fn parse_resolution(s: &str) -> Option<(u32, u32)> {
+    let (w, h) = s.trim().split_once(['x', 'X'])?;
+    let w: u32 = w.trim().parse().ok()?;
+    let h: u32 = h.trim().parse().ok()?;
+    if w == 0 || h == 0 {
+        return None;
+    }
+    Some((w, h))
+}
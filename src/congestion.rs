@@ -0,0 +1,470 @@
+//! Congestion-controlled adaptive bitrate
+//!
+//! Once a second the sender reads receiver reports from `webrtcbin` stats
+//! and, when `rtpgccbwe` is installed, the transport-wide congestion control
+//! (TWCC) bandwidth estimate. [`BitrateController`] turns those samples into
+//! an encoder bitrate, and steps frame rate and resolution down when the
+//! minimum bitrate still congests the link.
+//!
+//! The controller itself is pure so it can be driven by recorded or
+//! synthetic samples; `SIMULATE_LOSS` / `SIMULATE_DELAY` insert `netsim`
+//! before `webrtcbin` to exercise the whole loop against a lossy network.
+
+use crate::config::{env_flag, env_parse, env_string};
+use crate::encoder::EncoderControl;
+use crate::video_scaler::VideoScaler;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_BITRATE_KBPS: u32 = 4000;
+const DEFAULT_MIN_BITRATE_KBPS: u32 = 300;
+const DEFAULT_MAX_BITRATE_KBPS: u32 = 8000;
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Loss above which the bitrate is cut
+const HIGH_LOSS: f64 = 0.10;
+/// Loss below which the bitrate may grow
+const LOW_LOSS: f64 = 0.02;
+/// Consecutive congested samples at minimum bitrate before degrading
+const DEGRADE_AFTER: u32 = 3;
+/// Consecutive clear samples with headroom before restoring quality
+const RESTORE_AFTER: u32 = 5;
+
+/// Header extension URI for transport-wide congestion control
+pub const TWCC_URI: &str =
+    "http://www.ietf.org/id/draft-holmer-rmcat-transport-wide-cc-extensions-01";
+
+/// Frame rate divisor and resolution scale, from best to worst
+const QUALITY_LADDER: [QualityLevel; 5] = [
+    QualityLevel { fps_divisor: 1, scale: 1.0 },
+    QualityLevel { fps_divisor: 2, scale: 1.0 },
+    QualityLevel { fps_divisor: 2, scale: 0.75 },
+    QualityLevel { fps_divisor: 2, scale: 0.5 },
+    QualityLevel { fps_divisor: 3, scale: 0.5 },
+];
+
+/// Bitrate settings
+#[derive(Debug, Clone)]
+pub struct CongestionConfig {
+    /// Adapt to network conditions; otherwise stay at `start_kbps`
+    pub adaptive: bool,
+    pub start_kbps: u32,
+    pub min_kbps: u32,
+    pub max_kbps: u32,
+    /// Fraction of outgoing video packets to drop, for testing
+    pub simulate_loss: Option<f64>,
+    /// Added outgoing delay in milliseconds, for testing
+    pub simulate_delay: Option<u32>,
+}
+
+impl Default for CongestionConfig {
+    fn default() -> Self {
+        Self {
+            adaptive: true,
+            start_kbps: DEFAULT_BITRATE_KBPS,
+            min_kbps: DEFAULT_MIN_BITRATE_KBPS,
+            max_kbps: DEFAULT_MAX_BITRATE_KBPS,
+            simulate_loss: None,
+            simulate_delay: None,
+        }
+    }
+}
+
+impl CongestionConfig {
+    /// Read `BITRATE`, `MIN_BITRATE`, `MAX_BITRATE`, `ADAPTIVE_BITRATE` and
+    /// `SIMULATE_*` from the environment
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let start_kbps = env_parse("BITRATE").unwrap_or(defaults.start_kbps);
+        let min_kbps = env_parse("MIN_BITRATE").unwrap_or(defaults.min_kbps).min(start_kbps);
+        let max_kbps = env_parse("MAX_BITRATE").unwrap_or(defaults.max_kbps).max(start_kbps);
+
+        Self {
+            adaptive: env_string("ADAPTIVE_BITRATE").is_none() || env_flag("ADAPTIVE_BITRATE"),
+            start_kbps,
+            min_kbps,
+            max_kbps,
+            simulate_loss: env_parse::<f64>("SIMULATE_LOSS").map(|p| p.clamp(0.0, 1.0)),
+            simulate_delay: env_parse("SIMULATE_DELAY"),
+        }
+    }
+}
+
+/// One step of the degradation ladder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityLevel {
+    /// Capture frame rate is divided by this
+    pub fps_divisor: u32,
+    /// Output resolution is multiplied by this
+    pub scale: f64,
+}
+
+/// Network conditions over one sample interval
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetworkSample {
+    /// Fraction of packets lost (0.0 - 1.0) from receiver reports
+    pub loss: f64,
+    /// Round-trip time in milliseconds
+    pub rtt_ms: Option<f64>,
+    /// Bandwidth estimate from TWCC or REMB
+    pub estimate_kbps: Option<u32>,
+}
+
+/// What the sender should produce
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SendTarget {
+    pub bitrate_kbps: u32,
+    pub quality: QualityLevel,
+}
+
+/// Loss- and estimate-based bitrate controller in the style of Google
+/// Congestion Control, with frame rate / resolution degradation
+#[derive(Debug)]
+pub struct BitrateController {
+    min_kbps: f64,
+    max_kbps: f64,
+    bitrate: f64,
+    level: usize,
+    /// Consecutive congested samples at minimum bitrate
+    congested: u32,
+    /// Consecutive clear samples with bitrate headroom
+    clear: u32,
+}
+
+impl BitrateController {
+    pub fn new(config: &CongestionConfig) -> Self {
+        Self {
+            min_kbps: config.min_kbps as f64,
+            max_kbps: config.max_kbps as f64,
+            bitrate: config.start_kbps as f64,
+            level: 0,
+            congested: 0,
+            clear: 0,
+        }
+    }
+
+    /// Restart from a client-chosen bitrate, which also becomes the ceiling
+    pub fn set_bitrate(&mut self, kbps: u32) {
+        self.max_kbps = (kbps as f64).max(self.min_kbps);
+        self.bitrate = self.max_kbps;
+    }
+
+    pub fn target(&self) -> SendTarget {
+        SendTarget {
+            bitrate_kbps: self.bitrate.round() as u32,
+            quality: QUALITY_LADDER[self.level],
+        }
+    }
+
+    /// Feed one sample and return the new target
+    pub fn update(&mut self, sample: NetworkSample) -> SendTarget {
+        let loss = sample.loss.clamp(0.0, 1.0);
+
+        if loss > HIGH_LOSS {
+            self.bitrate *= 1.0 - 0.5 * loss;
+        } else if loss < LOW_LOSS {
+            self.bitrate = self.bitrate * 1.05 + 10.0;
+        }
+        if let Some(estimate) = sample.estimate_kbps {
+            self.bitrate = self.bitrate.min(estimate as f64);
+        }
+        self.bitrate = self.bitrate.clamp(self.min_kbps, self.max_kbps);
+
+        // Bitrate alone is not enough: the link is congested at the floor
+        let at_floor = self.bitrate <= self.min_kbps * 1.05;
+        let starved = sample.estimate_kbps.is_some_and(|e| (e as f64) < self.min_kbps);
+        if at_floor && (loss > HIGH_LOSS || starved) {
+            self.congested += 1;
+        } else {
+            self.congested = 0;
+        }
+
+        // Recovered enough to afford the next better level
+        let headroom = self.bitrate >= self.max_kbps * 0.5;
+        if loss < LOW_LOSS && headroom {
+            self.clear += 1;
+        } else {
+            self.clear = 0;
+        }
+
+        if self.congested >= DEGRADE_AFTER && self.level + 1 < QUALITY_LADDER.len() {
+            self.level += 1;
+            self.congested = 0;
+        } else if self.clear >= RESTORE_AFTER && self.level > 0 {
+            self.level -= 1;
+            self.clear = 0;
+        }
+
+        self.target()
+    }
+}
+
+/// Whether TWCC bandwidth estimation is available
+pub fn twcc_available() -> bool {
+    gst::ElementFactory::find("rtpgccbwe").is_some()
+}
+
+/// Route RTP through `rtpgccbwe` and record its estimate (in bit/s)
+pub fn install_bandwidth_estimator(
+    webrtcbin: &gst::Element,
+    config: &CongestionConfig,
+) -> Arc<AtomicU32> {
+    let estimate = Arc::new(AtomicU32::new(0));
+    let min_bps = config.min_kbps.saturating_mul(1000);
+    let max_bps = config.max_kbps.saturating_mul(1000);
+    let start_bps = config.start_kbps.saturating_mul(1000);
+
+    let estimate_store = estimate.clone();
+    webrtcbin.connect("request-aux-sender", false, move |_| {
+        let bwe = gst::ElementFactory::make("rtpgccbwe")
+            .property("min-bitrate", min_bps)
+            .property("max-bitrate", max_bps)
+            .property("estimated-bitrate", start_bps)
+            .build()
+            .ok()?;
+
+        let estimate = estimate_store.clone();
+        bwe.connect_notify(Some("estimated-bitrate"), move |bwe, _| {
+            estimate.store(bwe.property::<u32>("estimated-bitrate"), Ordering::Relaxed);
+        });
+
+        tracing::info!("TWCC bandwidth estimation enabled");
+        Some(bwe.to_value())
+    });
+
+    estimate
+}
+
+/// `netsim` element dropping and delaying packets, if configured
+pub fn create_network_simulator(config: &CongestionConfig) -> Option<gst::Element> {
+    if config.simulate_loss.is_none() && config.simulate_delay.is_none() {
+        return None;
+    }
+
+    let mut builder = gst::ElementFactory::make("netsim");
+    if let Some(loss) = config.simulate_loss {
+        builder = builder.property("drop-probability", loss as f32);
+    }
+    if let Some(delay) = config.simulate_delay {
+        builder = builder
+            .property("min-delay", delay as i32)
+            .property("max-delay", delay as i32)
+            .property("delay-probability", 1.0f32);
+    }
+
+    match builder.build() {
+        Ok(netsim) => {
+            tracing::warn!(
+                "Simulating network: loss {:?}, delay {:?} ms",
+                config.simulate_loss, config.simulate_delay
+            );
+            Some(netsim)
+        }
+        Err(e) => {
+            tracing::warn!("Network simulation unavailable (netsim missing): {}", e);
+            None
+        }
+    }
+}
+
+/// Periodically sample `webrtcbin` stats and apply the controller's target
+pub fn spawn_control_loop(
+    config: &CongestionConfig,
+    webrtcbin: gst::Element,
+    encoder: Arc<EncoderControl>,
+    scaler: Arc<VideoScaler>,
+    estimate_bps: Option<Arc<AtomicU32>>,
+) -> tokio::task::AbortHandle {
+    let mut controller = BitrateController::new(config);
+
+    tokio::spawn(async move {
+        let mut applied = controller.target();
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Some(kbps) = encoder.take_requested_bitrate() {
+                controller.set_bitrate(kbps);
+                applied.bitrate_kbps = kbps;
+            }
+
+            let Some(sample) = sample_network(&webrtcbin, estimate_bps.as_deref()).await else {
+                continue;
+            };
+            let target = controller.update(sample);
+            if target == applied {
+                continue;
+            }
+
+            tracing::debug!(
+                "Congestion control: loss {:.1}%, rtt {:?} ms, estimate {:?} kbps -> {:?}",
+                sample.loss * 100.0, sample.rtt_ms, sample.estimate_kbps, target
+            );
+            if target.bitrate_kbps != applied.bitrate_kbps {
+                encoder.set_current_bitrate(target.bitrate_kbps);
+            }
+            if target.quality != applied.quality {
+                tracing::info!(
+                    "Congestion control: frame rate / {}, resolution x{}",
+                    target.quality.fps_divisor, target.quality.scale
+                );
+                scaler.set_quality(target.quality.fps_divisor, target.quality.scale);
+            }
+            applied = target;
+        }
+    })
+    .abort_handle()
+}
+
+/// Read loss and RTT from `webrtcbin` stats plus the TWCC estimate (in bit/s)
+pub async fn sample_network(
+    webrtcbin: &gst::Element,
+    estimate_bps: Option<&AtomicU32>,
+) -> Option<NetworkSample> {
+    let (promise, reply) = gst::Promise::new_future();
+    webrtcbin.emit_by_name::<()>("get-stats", &[&None::<gst::Pad>, &promise]);
+    let Ok(Some(stats)) = reply.await else { return None };
+
+    let mut sample = parse_stats(&stats);
+    sample.estimate_kbps = estimate_bps
+        .map(|e| e.load(Ordering::Relaxed) / 1000)
+        .filter(|&kbps| kbps > 0);
+    Some(sample)
+}
+
+/// Worst loss and RTT over the video receiver reports
+fn parse_stats(stats: &gst::StructureRef) -> NetworkSample {
+    let mut sample = NetworkSample::default();
+
+    for (_, value) in stats.iter() {
+        let Ok(stat) = value.get::<gst::Structure>() else { continue };
+        if stat.name() != "remote-inbound-rtp" {
+            continue;
+        }
+
+        if let Ok(loss) = stat.get::<f64>("fraction-lost") {
+            sample.loss = sample.loss.max(loss);
+        }
+        if let Ok(rtt) = stat.get::<f64>("round-trip-time") {
+            let rtt_ms = rtt * 1000.0;
+            sample.rtt_ms = Some(sample.rtt_ms.map_or(rtt_ms, |r| r.max(rtt_ms)));
+        }
+    }
+
+    sample
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lossy(loss: f64) -> NetworkSample {
+        NetworkSample { loss, rtt_ms: Some(120.0), estimate_kbps: None }
+    }
+
+    fn clear() -> NetworkSample {
+        NetworkSample { loss: 0.0, rtt_ms: Some(30.0), estimate_kbps: None }
+    }
+
+    /// Feed `sample` until the quality level changes, returning the new target
+    fn until_level_changes(controller: &mut BitrateController, sample: NetworkSample) -> SendTarget {
+        let before = controller.target().quality;
+        for _ in 0..500 {
+            let target = controller.update(sample);
+            if target.quality != before {
+                return target;
+            }
+        }
+        panic!("quality stayed at {:?}", before);
+    }
+
+    #[test]
+    fn loss_lowers_bitrate_to_the_floor() {
+        let config = CongestionConfig::default();
+        let mut controller = BitrateController::new(&config);
+
+        let mut last = controller.target().bitrate_kbps;
+        for _ in 0..5 {
+            let target = controller.update(lossy(0.2));
+            assert!(target.bitrate_kbps < last);
+            assert_eq!(target.quality, QUALITY_LADDER[0]);
+            last = target.bitrate_kbps;
+        }
+        for _ in 0..50 {
+            controller.update(lossy(0.2));
+        }
+        assert_eq!(controller.target().bitrate_kbps, config.min_kbps);
+    }
+
+    #[test]
+    fn moderate_loss_holds_bitrate() {
+        let mut controller = BitrateController::new(&CongestionConfig::default());
+        let start = controller.target();
+        assert_eq!(controller.update(lossy(0.05)), start);
+    }
+
+    #[test]
+    fn twcc_estimate_caps_bitrate() {
+        let mut controller = BitrateController::new(&CongestionConfig::default());
+        let sample = NetworkSample { estimate_kbps: Some(1500), ..clear() };
+        assert_eq!(controller.update(sample).bitrate_kbps, 1500);
+    }
+
+    #[test]
+    fn congestion_at_the_floor_drops_frame_rate_then_resolution() {
+        let config = CongestionConfig::default();
+        let mut controller = BitrateController::new(&config);
+
+        // Bitrate goes first; quality holds until the floor still congests
+        let target = until_level_changes(&mut controller, lossy(0.3));
+        assert_eq!(target.bitrate_kbps, config.min_kbps);
+        assert_eq!(target.quality, QualityLevel { fps_divisor: 2, scale: 1.0 });
+
+        let target = until_level_changes(&mut controller, lossy(0.3));
+        assert_eq!(target.quality, QualityLevel { fps_divisor: 2, scale: 0.75 });
+        let target = until_level_changes(&mut controller, lossy(0.3));
+        assert_eq!(target.quality, QualityLevel { fps_divisor: 2, scale: 0.5 });
+        let target = until_level_changes(&mut controller, lossy(0.3));
+        assert_eq!(target.quality, QualityLevel { fps_divisor: 3, scale: 0.5 });
+
+        // Worst level is sticky
+        for _ in 0..20 {
+            assert_eq!(controller.update(lossy(0.3)).quality, QUALITY_LADDER[4]);
+        }
+    }
+
+    #[test]
+    fn starved_estimate_degrades_without_loss() {
+        let mut controller = BitrateController::new(&CongestionConfig::default());
+        let sample = NetworkSample { estimate_kbps: Some(100), ..clear() };
+        let target = until_level_changes(&mut controller, sample);
+        assert_eq!(target.quality, QUALITY_LADDER[1]);
+    }
+
+    #[test]
+    fn recovers_once_the_link_clears() {
+        let config = CongestionConfig::default();
+        let mut controller = BitrateController::new(&config);
+        for _ in 0..100 {
+            controller.update(lossy(0.3));
+        }
+        assert_eq!(controller.target().quality, QUALITY_LADDER[4]);
+
+        // Quality climbs back one level at a time, only once bitrate has recovered
+        let target = until_level_changes(&mut controller, clear());
+        assert!(target.bitrate_kbps as f64 >= config.max_kbps as f64 * 0.5);
+        assert_eq!(target.quality, QUALITY_LADDER[3]);
+        for level in (0..3).rev() {
+            assert_eq!(until_level_changes(&mut controller, clear()).quality, QUALITY_LADDER[level]);
+        }
+
+        for _ in 0..50 {
+            controller.update(clear());
+        }
+        assert_eq!(controller.target().bitrate_kbps, config.max_kbps);
+    }
+}
@@ -0,0 +1,282 @@
+//! Client-side cursor
+//!
+//! With `CLIENT_CURSOR=1` the pointer is left out of the captured video and
+//! drawn by the browser instead, so it neither waits for the encoder nor
+//! blurs at low bitrates. Its position is polled from the X server and its
+//! shape fetched with XFixes when the server reports a change; both are sent
+//! over a `cursor` data channel. Each shape is sent once per session and
+//! referenced by hash afterwards.
+
+use crate::input::InputTransform;
+use base64::Engine;
+use gstreamer_webrtc as gst_webrtc;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Messages on the cursor data channel
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CursorMessage {
+    /// Cursor shape now in use; `image` (RGBA, base64) only the first time a hash is seen
+    Shape {
+        hash: String,
+        width: u32,
+        height: u32,
+        xhot: u32,
+        yhot: u32,
+        image: Option<String>,
+    },
+    /// Pointer position in video pixels; `scale` is video pixels per screen pixel
+    Position { x: f64, y: f64, scale: f64 },
+}
+
+/// Cursor image as read from the X server
+#[derive(Debug, Clone)]
+struct CursorShape {
+    width: u32,
+    height: u32,
+    xhot: u32,
+    yhot: u32,
+    /// Premultiplied ARGB
+    pixels: Vec<u32>,
+}
+
+/// Straight-alpha RGBA bytes from premultiplied ARGB pixels
+fn to_rgba(pixels: &[u32]) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(pixels.len() * 4);
+    for &p in pixels {
+        let a = p >> 24;
+        let unpremultiply = |c: u32| if a == 0 { 0 } else { (c * 255 / a).min(255) as u8 };
+        rgba.extend([
+            unpremultiply((p >> 16) & 0xff),
+            unpremultiply((p >> 8) & 0xff),
+            unpremultiply(p & 0xff),
+            a as u8,
+        ]);
+    }
+    rgba
+}
+
+fn shape_hash(shape: &CursorShape) -> String {
+    use sha1::{Digest, Sha1};
+    let mut hasher = Sha1::new();
+    for v in [shape.width, shape.height, shape.xhot, shape.yhot] {
+        hasher.update(v.to_le_bytes());
+    }
+    for p in &shape.pixels {
+        hasher.update(p.to_le_bytes());
+    }
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use super::CursorShape;
+    use anyhow::{Context, Result};
+    use x11rb::connection::{Connection, RequestConnection};
+    use x11rb::protocol::xfixes::{self, ConnectionExt as _};
+    use x11rb::protocol::xproto::{ConnectionExt as _, Window};
+    use x11rb::protocol::Event;
+    use x11rb::rust_connection::RustConnection;
+
+    pub struct CursorReader {
+        conn: RustConnection,
+        root: Window,
+    }
+
+    impl CursorReader {
+        /// Connect and subscribe to cursor shape changes on the root window
+        pub fn connect(display: Option<&str>) -> Result<Self> {
+            let (conn, screen) = x11rb::connect(display).context("Cannot connect to X display")?;
+            conn.extension_information(xfixes::X11_EXTENSION_NAME)?
+                .context("X server lacks the XFixes extension")?;
+            conn.xfixes_query_version(4, 0)?.reply()?;
+            let root = conn.setup().roots[screen].root;
+            conn.xfixes_select_cursor_input(root, xfixes::CursorNotifyMask::DISPLAY_CURSOR)?
+                .check()?;
+            Ok(Self { conn, root })
+        }
+
+        /// Pointer position in root window coordinates
+        pub fn position(&self) -> Result<(i32, i32)> {
+            let reply = self.conn.query_pointer(self.root)?.reply()?;
+            Ok((reply.root_x as i32, reply.root_y as i32))
+        }
+
+        /// Drain pending events; true if the displayed cursor changed since the last call
+        pub fn shape_changed(&self) -> Result<bool> {
+            let mut changed = false;
+            while let Some(event) = self.conn.poll_for_event()? {
+                changed |= matches!(event, Event::XfixesCursorNotify(_));
+            }
+            Ok(changed)
+        }
+
+        pub fn shape(&self) -> Result<CursorShape> {
+            let reply = self.conn.xfixes_get_cursor_image()?.reply()?;
+            Ok(CursorShape {
+                width: reply.width as u32,
+                height: reply.height as u32,
+                xhot: reply.xhot as u32,
+                yhot: reply.yhot as u32,
+                pixels: reply.cursor_image,
+            })
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod x11 {
+    use super::CursorShape;
+    use anyhow::Result;
+
+    pub struct CursorReader;
+
+    impl CursorReader {
+        pub fn connect(_display: Option<&str>) -> Result<Self> {
+            anyhow::bail!("Client-side cursor needs X11")
+        }
+
+        pub fn position(&self) -> Result<(i32, i32)> {
+            unreachable!()
+        }
+
+        pub fn shape_changed(&self) -> Result<bool> {
+            unreachable!()
+        }
+
+        pub fn shape(&self) -> Result<CursorShape> {
+            unreachable!()
+        }
+    }
+}
+
+/// Whether the pointer can be drawn by the client on this platform
+pub fn supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// Handle to a running cursor tracker; stops it when aborted or dropped
+pub struct CursorTracker {
+    stop: Arc<AtomicBool>,
+}
+
+impl CursorTracker {
+    pub fn abort(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for CursorTracker {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+/// Send cursor updates over `channel` until aborted. Positions are mapped
+/// into video pixels with the session's input transform.
+///
+/// Runs on its own thread: the pointer position is polled with a cheap
+/// `QueryPointer`, while the cursor image is only fetched after XFixes
+/// reports a shape change.
+pub fn spawn_tracker(
+    display: Option<String>,
+    channel: gst_webrtc::WebRTCDataChannel,
+    transform: Arc<Mutex<InputTransform>>,
+) -> CursorTracker {
+    let stop = Arc::new(AtomicBool::new(false));
+    let tracker = CursorTracker { stop: stop.clone() };
+
+    let spawned = std::thread::Builder::new()
+        .name("cursor".to_string())
+        .spawn(move || {
+            let reader = match x11::CursorReader::connect(display.as_deref()) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    tracing::warn!("Cursor tracking unavailable: {:#}", e);
+                    return;
+                }
+            };
+            if let Err(e) = track(&reader, &channel, &transform, &stop) {
+                tracing::warn!("Cursor tracking stopped: {}", e);
+            }
+        });
+    if let Err(e) = spawned {
+        tracing::warn!("Cannot start cursor tracking: {}", e);
+    }
+
+    tracker
+}
+
+fn track(
+    reader: &x11::CursorReader,
+    channel: &gst_webrtc::WebRTCDataChannel,
+    transform: &Mutex<InputTransform>,
+    stop: &AtomicBool,
+) -> anyhow::Result<()> {
+    let mut sent_hashes = HashSet::new();
+    // Current shape and its hash; fetched lazily once a change is reported
+    let mut shape: Option<(String, CursorShape)> = None;
+    let mut shape_dirty = true;
+    let mut shape_sent = false;
+    let mut last_position = None;
+
+    while !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(POLL_INTERVAL);
+
+        shape_dirty |= reader.shape_changed()?;
+
+        // Start over whenever the channel (re)opens
+        if channel.ready_state() != gst_webrtc::WebRTCDataChannelState::Open {
+            sent_hashes.clear();
+            shape_sent = false;
+            last_position = None;
+            continue;
+        }
+
+        if shape_dirty {
+            let current = reader.shape()?;
+            shape = Some((shape_hash(&current), current));
+            shape_dirty = false;
+            shape_sent = false;
+        }
+        if let Some((hash, current)) = shape.as_ref().filter(|_| !shape_sent) {
+            shape_sent = true;
+            let image = sent_hashes
+                .insert(hash.clone())
+                .then(|| base64::engine::general_purpose::STANDARD.encode(to_rgba(&current.pixels)));
+            send(channel, &CursorMessage::Shape {
+                hash: hash.clone(),
+                width: current.width,
+                height: current.height,
+                xhot: current.xhot,
+                yhot: current.yhot,
+                image,
+            });
+        }
+
+        let (x, y) = reader.position()?;
+        let t = *transform.lock().unwrap();
+        let key = (x, y, t.origin_x, t.origin_y, t.scale_x.to_bits(), t.scale_y.to_bits());
+        if last_position != Some(key) {
+            last_position = Some(key);
+            send(channel, &CursorMessage::Position {
+                x: (x - t.origin_x) as f64 / t.scale_x,
+                y: (y - t.origin_y) as f64 / t.scale_y,
+                scale: 1.0 / t.scale_x,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn send(channel: &gst_webrtc::WebRTCDataChannel, message: &CursorMessage) {
+    if let Ok(json) = serde_json::to_string(message) {
+        channel.send_string(Some(&json));
+    }
+}
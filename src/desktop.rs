@@ -0,0 +1,232 @@
+//! Per-user isolated desktops
+//!
+//! Each authenticated user gets a private virtual display and input target,
+//! created on first connect and kept alive for a while after the last
+//! session disconnects so users can pick up where they left off.
+
+use crate::config::{env_flag, env_parse};
+use crate::input::{start_input_thread, InputEvent};
+use crate::virtual_display::{VirtualDisplay, VirtualDisplayConfig};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const DEFAULT_KEEPALIVE_SECS: u64 = 300;
+const DEFAULT_MAX_DESKTOPS: usize = 10;
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Per-user desktop settings
+#[derive(Debug, Clone)]
+pub struct DesktopConfig {
+    /// Settings for each user's virtual display
+    pub display: VirtualDisplayConfig,
+    /// How long a desktop survives after its last session disconnects
+    pub keepalive: Duration,
+    /// Maximum number of desktops alive at once
+    pub max_desktops: usize,
+}
+
+impl DesktopConfig {
+    /// Read `PER_USER_DESKTOPS` and `DESKTOP_*` settings; `None` when disabled
+    pub fn from_env() -> Option<Self> {
+        if !env_flag("PER_USER_DESKTOPS") {
+            return None;
+        }
+
+        let display = VirtualDisplayConfig {
+            display: None,
+            ..VirtualDisplayConfig::from_env()
+        };
+
+        Some(Self {
+            display,
+            keepalive: Duration::from_secs(
+                env_parse("DESKTOP_KEEPALIVE").unwrap_or(DEFAULT_KEEPALIVE_SECS),
+            ),
+            max_desktops: env_parse("MAX_DESKTOPS").unwrap_or(DEFAULT_MAX_DESKTOPS),
+        })
+    }
+}
+
+/// A user's desktop
+struct Desktop {
+    display: VirtualDisplay,
+    input_tx: mpsc::UnboundedSender<InputEvent>,
+    /// Number of connected sessions
+    sessions: usize,
+    /// When the last session disconnected
+    idle_since: Option<Instant>,
+}
+
+/// Owns all per-user desktops
+pub struct DesktopManager {
+    config: DesktopConfig,
+    desktops: Mutex<HashMap<String, Desktop>>,
+}
+
+impl DesktopManager {
+    pub fn new(config: DesktopConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            desktops: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Attach a session to the user's desktop, creating it if needed.
+    ///
+    /// Blocks while a new virtual display starts up. The display is spawned
+    /// and evicted desktops are torn down without holding the lock, so other
+    /// users' sessions are not held up.
+    pub fn acquire(self: &Arc<Self>, user: &str) -> Result<DesktopLease> {
+        let evicted = {
+            let mut desktops = self.desktops.lock().unwrap();
+            if let Some(desktop) = desktops.get_mut(user) {
+                return Ok(self.lease(user, desktop));
+            }
+            self.make_room(&mut desktops)?
+        };
+        drop(evicted);
+
+        // Declared before the guard so an unused display is dropped after unlocking
+        let virtual_display = VirtualDisplay::spawn(&self.config.display)?;
+
+        let mut desktops = self.desktops.lock().unwrap();
+        // Another session of the same user may have created one meanwhile
+        if let Some(desktop) = desktops.get_mut(user) {
+            return Ok(self.lease(user, desktop));
+        }
+        // Other users may have taken the room freed above
+        let evicted = self.make_room(&mut desktops)?;
+
+        let input_tx = start_input_thread(Some(virtual_display.name().to_string()));
+        tracing::info!("Created desktop {} for user '{}'", virtual_display.name(), user);
+
+        let desktop = desktops.entry(user.to_string()).or_insert(Desktop {
+            display: virtual_display,
+            input_tx,
+            sessions: 0,
+            idle_since: None,
+        });
+        let lease = self.lease(user, desktop);
+        drop(desktops);
+        drop(evicted);
+        Ok(lease)
+    }
+
+    /// Count a new session on `desktop`
+    fn lease(self: &Arc<Self>, user: &str, desktop: &mut Desktop) -> DesktopLease {
+        desktop.sessions += 1;
+        desktop.idle_since = None;
+
+        DesktopLease {
+            manager: self.clone(),
+            user: user.to_string(),
+            display_name: desktop.display.name().to_string(),
+            input_tx: desktop.input_tx.clone(),
+        }
+    }
+
+    /// Make room for one more desktop, returning the evicted one (if any) for
+    /// the caller to drop once the lock is released
+    fn make_room(&self, desktops: &mut HashMap<String, Desktop>) -> Result<Option<Desktop>> {
+        if desktops.len() < self.config.max_desktops {
+            return Ok(None);
+        }
+        match evict_idle(desktops) {
+            Some(desktop) => Ok(Some(desktop)),
+            None => anyhow::bail!(
+                "All {} desktops are in use, try again later",
+                self.config.max_desktops
+            ),
+        }
+    }
+
+    fn release(&self, user: &str) {
+        let mut desktops = self.desktops.lock().unwrap();
+        if let Some(desktop) = desktops.get_mut(user) {
+            desktop.sessions = desktop.sessions.saturating_sub(1);
+            if desktop.sessions == 0 {
+                desktop.idle_since = Some(Instant::now());
+                tracing::info!(
+                    "Desktop {} for user '{}' idle, keeping for {:?}",
+                    desktop.display.name(), user, self.config.keepalive
+                );
+            }
+        }
+    }
+
+    /// Periodically destroy desktops that have been idle past the keepalive
+    pub fn spawn_reaper(self: &Arc<Self>) {
+        let manager = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REAP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(manager) = manager.upgrade() else { break };
+
+                let expired: Vec<Desktop> = {
+                    let mut desktops = manager.desktops.lock().unwrap();
+                    let keepalive = manager.config.keepalive;
+                    let users: Vec<String> = desktops
+                        .iter()
+                        .filter(|(_, d)| d.idle_since.is_some_and(|t| t.elapsed() >= keepalive))
+                        .map(|(user, _)| user.clone())
+                        .collect();
+                    users
+                        .iter()
+                        .filter_map(|user| {
+                            tracing::info!("Desktop for user '{}' expired", user);
+                            desktops.remove(user)
+                        })
+                        .collect()
+                };
+
+                // Tearing down X servers blocks, keep it off the async workers
+                if !expired.is_empty() {
+                    let _ = tokio::task::spawn_blocking(move || drop(expired)).await;
+                }
+            }
+        });
+    }
+}
+
+/// Remove the longest-idle desktop to make room; `None` if none is idle
+fn evict_idle(desktops: &mut HashMap<String, Desktop>) -> Option<Desktop> {
+    let oldest = desktops
+        .iter()
+        .filter_map(|(user, d)| d.idle_since.map(|t| (user.clone(), t)))
+        .min_by_key(|(_, t)| *t)
+        .map(|(user, _)| user);
+
+    let user = oldest?;
+    tracing::info!("Evicting idle desktop for user '{}'", user);
+    desktops.remove(&user)
+}
+
+/// A session's claim on a user desktop, released when dropped
+pub struct DesktopLease {
+    manager: Arc<DesktopManager>,
+    user: String,
+    display_name: String,
+    input_tx: mpsc::UnboundedSender<InputEvent>,
+}
+
+impl DesktopLease {
+    /// X display of the desktop
+    pub fn display_name(&self) -> &str {
+        &self.display_name
+    }
+
+    /// Input channel of the desktop
+    pub fn input_tx(&self) -> mpsc::UnboundedSender<InputEvent> {
+        self.input_tx.clone()
+    }
+}
+
+impl Drop for DesktopLease {
+    fn drop(&mut self) {
+        self.manager.release(&self.user);
+    }
+}
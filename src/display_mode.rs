@@ -0,0 +1,180 @@
+//! Resizing X screens via XRandR
+//!
+//! For RDP-style dynamic resolution the remote screen is switched to a mode
+//! matching the client viewport, adding the mode first if the output does
+//! not have it. Works with Xvfb (up to its initial screen size) and with
+//! RandR-capable servers such as Xvnc.
+
+use anyhow::{Context, Result};
+use std::process::Command;
+
+/// Screen and output state from `xrandr --query`
+#[derive(Debug, Default)]
+struct ScreenModes {
+    current: Option<(u32, u32)>,
+    maximum: Option<(u32, u32)>,
+    /// First connected output
+    output: Option<String>,
+    /// Mode names of that output
+    modes: Vec<String>,
+}
+
+/// Switch the screen of an X display (`$DISPLAY` when `None`) to
+/// `width`x`height`, clamped to what the server supports.
+///
+/// Returns the new screen size, or `None` if it already had that size.
+pub fn resize_screen(display: Option<&str>, width: u32, height: u32) -> Result<Option<(u32, u32)>> {
+    let screen = parse_query(&xrandr(display, &["--query"])?);
+
+    let (max_width, max_height) = screen.maximum.unwrap_or((u32::MAX, u32::MAX));
+    // Encoders need even dimensions
+    let width = (width.min(max_width) & !1).max(2);
+    let height = (height.min(max_height) & !1).max(2);
+
+    if screen.current == Some((width, height)) {
+        return Ok(None);
+    }
+
+    let name = format!("{}x{}", width, height);
+    match &screen.output {
+        Some(output) => {
+            if !screen.modes.contains(&name) {
+                // May already exist unattached to the output
+                let mut args = vec!["--newmode".to_string(), name.clone()];
+                args.extend(modeline(width, height));
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                if let Err(e) = xrandr(display, &args) {
+                    tracing::debug!("xrandr --newmode {}: {}", name, e);
+                }
+                xrandr(display, &["--addmode", output, &name])?;
+            }
+            xrandr(display, &["--output", output, "--mode", &name])?;
+        }
+        // Servers without outputs can still resize the framebuffer
+        None => {
+            xrandr(display, &["--fb", &name])?;
+        }
+    }
+
+    Ok(Some((width, height)))
+}
+
+fn xrandr(display: Option<&str>, args: &[&str]) -> Result<String> {
+    let mut cmd = Command::new("xrandr");
+    cmd.args(args);
+    if let Some(display) = display {
+        cmd.env("DISPLAY", display);
+    }
+
+    let output = cmd.output().context("Failed to run xrandr — is it installed?")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "xrandr failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Timings for a 60 Hz mode with minimal blanking; virtual displays do not
+/// care about the exact values
+fn modeline(width: u32, height: u32) -> Vec<String> {
+    let (htotal, vtotal) = (width + 24, height + 3);
+    let clock = htotal as f64 * vtotal as f64 * 60.0 / 1_000_000.0;
+    let mut fields = vec![format!("{:.2}", clock)];
+    fields.extend([width, width + 8, width + 16, htotal].map(|v| v.to_string()));
+    fields.extend([height, height + 1, height + 2, vtotal].map(|v| v.to_string()));
+    fields
+}
+
+/// Parse `xrandr --query` output:
+///
+/// ```text
+/// Screen 0: minimum 1 x 1, current 1920 x 1080, maximum 8192 x 8192
+/// screen connected primary 1920x1080+0+0 0mm x 0mm
+///    1920x1080     60.00*
+///    1280x720      60.00
+/// ```
+fn parse_query(output: &str) -> ScreenModes {
+    let mut screen = ScreenModes::default();
+    let mut in_output = false;
+
+    for line in output.lines() {
+        if let Some(header) = line.strip_prefix("Screen ") {
+            screen.current = parse_size_after(header, "current ");
+            screen.maximum = parse_size_after(header, "maximum ");
+        } else if line.starts_with(char::is_whitespace) {
+            if in_output {
+                if let Some(mode) = line.split_whitespace().next() {
+                    screen.modes.push(mode.to_string());
+                }
+            }
+        } else {
+            in_output = false;
+            let mut fields = line.split_whitespace();
+            if let (Some(name), Some("connected")) = (fields.next(), fields.next()) {
+                if screen.output.is_none() {
+                    screen.output = Some(name.to_string());
+                    in_output = true;
+                }
+            }
+        }
+    }
+
+    screen
+}
+
+/// Parse `W x H` following `label` in an xrandr screen header
+fn parse_size_after(header: &str, label: &str) -> Option<(u32, u32)> {
+    let rest = &header[header.find(label)? + label.len()..];
+    let mut fields = rest.split_whitespace();
+    let width = fields.next()?.parse().ok()?;
+    let _ = fields.next()?; // "x"
+    let height = fields.next()?.trim_end_matches(',').parse().ok()?;
+    Some((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `xrandr --query` on a laptop with an external monitor
+    const TWO_OUTPUTS: &str = "\
+Screen 0: minimum 320 x 200, current 2560 x 1440, maximum 16384 x 16384
+eDP-1 disconnected (normal left inverted right x axis y axis)
+DP-1 connected primary 2560x1440+0+0 (normal left inverted right x axis y axis) 597mm x 336mm
+   2560x1440     59.95*+
+   1920x1080     60.00    50.00    59.94
+   1280x720      60.00
+HDMI-1 connected 1920x1080+2560+0 (normal left inverted right x axis y axis) 527mm x 296mm
+   1920x1080     60.00*+
+   1024x768      60.00
+";
+
+    #[test]
+    fn parses_first_connected_output() {
+        let screen = parse_query(TWO_OUTPUTS);
+        assert_eq!(screen.current, Some((2560, 1440)));
+        assert_eq!(screen.maximum, Some((16384, 16384)));
+        assert_eq!(screen.output.as_deref(), Some("DP-1"));
+        assert_eq!(screen.modes, ["2560x1440", "1920x1080", "1280x720"]);
+    }
+
+    #[test]
+    fn parses_screen_without_outputs() {
+        let screen = parse_query("Screen 0: minimum 1 x 1, current 1280 x 720, maximum 1280 x 720\n");
+        assert_eq!(screen.current, Some((1280, 720)));
+        assert_eq!(screen.maximum, Some((1280, 720)));
+        assert_eq!(screen.output, None);
+        assert!(screen.modes.is_empty());
+    }
+
+    #[test]
+    fn parses_sizes_in_header() {
+        let header = "0: minimum 8 x 8, current 1920 x 1080, maximum 32767 x 32767";
+        assert_eq!(parse_size_after(header, "minimum "), Some((8, 8)));
+        assert_eq!(parse_size_after(header, "maximum "), Some((32767, 32767)));
+        assert_eq!(parse_size_after("0: current 1920", "current "), None);
+        assert_eq!(parse_size_after(header, "preferred "), None);
+    }
+}
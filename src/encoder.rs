@@ -0,0 +1,769 @@
+//! Video codec and encoder selection and control
+//!
+//! The codec is picked per session from the server's preference order and
+//! the codecs the client can decode. For H.264, hardware encoders are tried
+//! first with a software fallback. Each encoder names its properties
+//! differently, so settings are described once as [`EncoderSettings`] and
+//! translated per encoder by [`EncoderKind`].
+//!
+//! Some encoders build fine and only fail once the pipeline starts (e.g.
+//! NVENC without a working driver). [`await_startup`] watches for that, and
+//! failed encoders are skipped when the pipeline is rebuilt and for the
+//! next [`FAILURE_TTL`], so a transient failure does not rule them out for
+//! good.
+//!
+//! The `text` [`QualityProfile`] encodes full 4:4:4 chroma (H.264 High 4:4:4
+//! with x264, VP9 profile 1) when the client can decode it, and
+//! tunes the encoder for screen content so thin, coloured text stays sharp.
+
+use anyhow::Result;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a freshly started encoder has to produce output without errors
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Highest frame rate a client may ask for
+pub const MAX_FPS: u32 = 120;
+
+/// How long an encoder that failed after building is skipped
+pub const FAILURE_TTL: Duration = Duration::from_secs(600);
+
+/// Encoders that failed after building, and when
+static FAILED_ENCODERS: Mutex<Vec<(EncoderKind, Instant)>> = Mutex::new(Vec::new());
+
+/// Speed / quality trade-off
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderPreset {
+    /// Lowest latency and CPU use
+    #[default]
+    Fast,
+    Balanced,
+    /// Best quality that still encodes in real time
+    Quality,
+}
+
+impl EncoderPreset {
+    /// Choose the fast, balanced or quality value
+    fn pick<T>(self, fast: T, balanced: T, quality: T) -> T {
+        match self {
+            Self::Fast => fast,
+            Self::Balanced => balanced,
+            Self::Quality => quality,
+        }
+    }
+}
+
+impl FromStr for EncoderPreset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "fast" => Ok(Self::Fast),
+            "balanced" => Ok(Self::Balanced),
+            "quality" => Ok(Self::Quality),
+            _ => anyhow::bail!("Unknown encoder preset '{}'", s),
+        }
+    }
+}
+
+/// What the encoding is tuned for, chosen per session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QualityProfile {
+    /// 4:2:0 chroma, suited to video and general desktop use
+    #[default]
+    Standard,
+    /// 4:4:4 chroma where the client supports it, finer quantization
+    Text,
+}
+
+impl FromStr for QualityProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "standard" => Ok(Self::Standard),
+            "text" => Ok(Self::Text),
+            _ => anyhow::bail!("Unknown quality profile '{}'", s),
+        }
+    }
+}
+
+/// Encoder-independent settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncoderSettings {
+    /// Target bitrate in kbit/s
+    pub bitrate: u32,
+    /// Frames per second
+    pub fps: u32,
+    /// Maximum frames between keyframes
+    pub keyframe_interval: u32,
+    pub preset: EncoderPreset,
+    pub profile: QualityProfile,
+    /// Encoding full-resolution chroma; fixed when the encoder is created
+    pub chroma_444: bool,
+}
+
+/// Partial settings change requested by a client
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct EncoderUpdate {
+    pub bitrate: Option<u32>,
+    pub fps: Option<u32>,
+    pub keyframe_interval: Option<u32>,
+    pub preset: Option<EncoderPreset>,
+}
+
+/// Video codecs that can be streamed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    H264,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+impl Codec {
+    /// Server preference when `CODECS` is not set
+    pub const DEFAULT_PREFERENCE: [Codec; 4] = [Self::H264, Self::Vp8, Self::Vp9, Self::Av1];
+
+    /// RTP encoding name, as in SDP and WebRTC capabilities
+    pub fn encoding_name(self) -> &'static str {
+        match self {
+            Self::H264 => "H264",
+            Self::Vp8 => "VP8",
+            Self::Vp9 => "VP9",
+            Self::Av1 => "AV1",
+        }
+    }
+
+    /// Bitstream parser between encoder and payloader, if one is needed
+    pub fn create_parser(self) -> Result<Option<gst::Element>> {
+        Ok(match self {
+            Self::H264 => Some(
+                gst::ElementFactory::make("h264parse")
+                    .property("config-interval", -1i32)
+                    .build()?,
+            ),
+            Self::Vp8 | Self::Vp9 | Self::Av1 => None,
+        })
+    }
+
+    fn payloader_name(self) -> &'static str {
+        match self {
+            Self::H264 => "rtph264pay",
+            Self::Vp8 => "rtpvp8pay",
+            Self::Vp9 => "rtpvp9pay",
+            Self::Av1 => "rtpav1pay",
+        }
+    }
+
+    pub fn create_payloader(self) -> Result<gst::Element> {
+        let builder = gst::ElementFactory::make(self.payloader_name());
+        let builder = match self {
+            Self::H264 => builder
+                .property("config-interval", -1i32)
+                .property_from_str("aggregate-mode", "zero-latency"),
+            Self::Vp8 | Self::Vp9 => builder.property_from_str("picture-id-mode", "15-bit"),
+            Self::Av1 => builder,
+        };
+        Ok(builder.build()?)
+    }
+
+    /// Whether a payloader and at least one allowed encoder are installed
+    fn available(self, choice: &EncoderChoice) -> bool {
+        gst::ElementFactory::find(self.payloader_name()).is_some()
+            && choice
+                .kinds()
+                .iter()
+                .any(|k| k.codec() == self && gst::ElementFactory::find(k.factory_name()).is_some())
+    }
+
+    /// Codecs to try, in server preference order, limited to those the
+    /// client can decode (any, if it did not say) and that are installed
+    pub fn candidates(
+        preference: &[Codec],
+        client: Option<&[String]>,
+        choice: &EncoderChoice,
+    ) -> Vec<Codec> {
+        preference
+            .iter()
+            .copied()
+            .filter(|codec| {
+                client.map_or(true, |names| {
+                    names.iter().any(|n| n.eq_ignore_ascii_case(codec.encoding_name()))
+                })
+            })
+            .filter(|codec| codec.available(choice))
+            .collect()
+    }
+
+    /// Like [`candidates`](Self::candidates), limited to codecs the client
+    /// decodes at 4:4:4 and that have a 4:4:4 encoder installed
+    pub fn candidates_444(preference: &[Codec], client: &[String], choice: &EncoderChoice) -> Vec<Codec> {
+        Self::candidates(preference, Some(client), choice)
+            .into_iter()
+            .filter(|&codec| {
+                choice.kinds().iter().any(|k| {
+                    k.codec() == codec && k.supports_444() && gst::ElementFactory::find(k.factory_name()).is_some()
+                })
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_uppercase().as_str() {
+            "H264" => Ok(Self::H264),
+            "VP8" => Ok(Self::Vp8),
+            "VP9" => Ok(Self::Vp9),
+            "AV1" => Ok(Self::Av1),
+            _ => anyhow::bail!("Unknown codec '{}'", s),
+        }
+    }
+}
+
+/// Supported encoders, in order of preference within each codec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderKind {
+    VideoToolbox,
+    Nvenc,
+    Vaapi,
+    QuickSync,
+    X264,
+    Vp8,
+    Vp9,
+    SvtAv1,
+    Rav1e,
+    Aom,
+    /// Test stand-in that fails on its first frame
+    #[cfg(test)]
+    FakeFailing,
+    /// Test stand-in that passes frames through
+    #[cfg(test)]
+    Fake,
+}
+
+impl EncoderKind {
+    pub const ALL: [EncoderKind; 10] = [
+        Self::VideoToolbox,
+        Self::Nvenc,
+        Self::Vaapi,
+        Self::QuickSync,
+        Self::X264,
+        Self::Vp8,
+        Self::Vp9,
+        Self::SvtAv1,
+        Self::Rav1e,
+        Self::Aom,
+    ];
+
+    /// Look up an encoder by factory name
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim();
+        Self::ALL.into_iter().find(|k| k.factory_name() == name)
+    }
+
+    pub fn factory_name(self) -> &'static str {
+        match self {
+            Self::VideoToolbox => "vtenc_h264",
+            Self::Nvenc => "nvh264enc",
+            Self::Vaapi => "vaapih264enc",
+            Self::QuickSync => "qsvh264enc",
+            Self::X264 => "x264enc",
+            Self::Vp8 => "vp8enc",
+            Self::Vp9 => "vp9enc",
+            Self::SvtAv1 => "svtav1enc",
+            Self::Rav1e => "rav1enc",
+            Self::Aom => "av1enc",
+            #[cfg(test)]
+            Self::FakeFailing | Self::Fake => "identity",
+        }
+    }
+
+    pub fn codec(self) -> Codec {
+        match self {
+            Self::VideoToolbox
+            | Self::Nvenc
+            | Self::Vaapi
+            | Self::QuickSync
+            | Self::X264 => Codec::H264,
+            #[cfg(test)]
+            Self::FakeFailing | Self::Fake => Codec::H264,
+            Self::Vp8 => Codec::Vp8,
+            Self::Vp9 => Codec::Vp9,
+            Self::SvtAv1 | Self::Rav1e | Self::Aom => Codec::Av1,
+        }
+    }
+
+    /// Whether the encoder takes 4:4:4 input (`Y444`)
+    pub fn supports_444(self) -> bool {
+        matches!(self, Self::X264 | Self::Vp9)
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::VideoToolbox => "VideoToolbox hardware encoder",
+            Self::Nvenc => "NVIDIA NVENC hardware encoder",
+            Self::Vaapi => "VAAPI hardware encoder",
+            Self::QuickSync => "Intel QuickSync encoder",
+            Self::X264 => "x264 software encoder",
+            Self::Vp8 => "libvpx VP8 encoder",
+            Self::Vp9 => "libvpx VP9 encoder",
+            Self::SvtAv1 => "SVT-AV1 encoder",
+            Self::Rav1e => "rav1e AV1 encoder",
+            Self::Aom => "libaom AV1 encoder",
+            #[cfg(test)]
+            Self::FakeFailing => "fake failing encoder",
+            #[cfg(test)]
+            Self::Fake => "fake encoder",
+        }
+    }
+
+    /// Create the encoder with low-latency settings
+    fn build(self, settings: &EncoderSettings) -> Result<gst::Element, gst::glib::BoolError> {
+        let builder = gst::ElementFactory::make(self.factory_name());
+        let builder = match self {
+            Self::VideoToolbox => builder
+                .property("realtime", true)
+                .property("allow-frame-reordering", false),
+            Self::Nvenc => builder
+                .property_from_str("rc-mode", "cbr")
+                .property("zerolatency", true),
+            Self::Vaapi => builder
+                .property_from_str("rate-control", "cbr"),
+            Self::QuickSync => builder,
+            Self::X264 => builder
+                .property_from_str("tune", "zerolatency"),
+            Self::Vp8 | Self::Vp9 => builder
+                .property("deadline", 1i64) // realtime
+                .property_from_str("end-usage", "cbr")
+                .property("lag-in-frames", 0i32),
+            Self::SvtAv1 => builder,
+            Self::Rav1e => builder
+                .property("low-latency", true),
+            Self::Aom => builder
+                .property_from_str("usage-profile", "realtime")
+                .property_from_str("end-usage", "cbr")
+                .property("lag-in-frames", 0u32),
+            #[cfg(test)]
+            Self::FakeFailing => builder
+                .property("error-after", 1i32),
+            #[cfg(test)]
+            Self::Fake => builder,
+        };
+
+        let encoder = builder.build()?;
+        self.set_bitrate(&encoder, settings.bitrate);
+        self.set_keyframe_interval(&encoder, settings.keyframe_interval);
+        self.set_preset(&encoder, settings.preset);
+        if settings.profile == QualityProfile::Text {
+            self.tune_for_text(&encoder);
+        }
+        Ok(encoder)
+    }
+
+    /// Keep quantization fine, so static text stays sharp once the encoder
+    /// has refined it. Quantizer limits are left alone, so the encoder
+    /// still meets the bitrate congestion control sets.
+    fn tune_for_text(self, encoder: &gst::Element) {
+        match self {
+            Self::X264 => {
+                encoder.set_property("option-string", "aq-mode=2:deadzone-inter=6:deadzone-intra=6");
+            }
+            Self::Vp9 if encoder.has_property("tune-content", None) => {
+                encoder.set_property_from_str("tune-content", "screen");
+            }
+            _ => {}
+        }
+    }
+
+    /// Set the target bitrate in kbit/s
+    pub fn set_bitrate(self, encoder: &gst::Element, kbps: u32) {
+        let bps = kbps.saturating_mul(1000).min(i32::MAX as u32) as i32;
+        match self {
+            // bit/s
+            Self::Vp8 | Self::Vp9 => set_clamped(encoder, "target-bitrate", bps as i64),
+            Self::Rav1e => set_clamped(encoder, "bitrate", bps as i64),
+            Self::SvtAv1 | Self::Aom => set_clamped(encoder, "target-bitrate", kbps as i64),
+            #[cfg(test)]
+            Self::FakeFailing | Self::Fake => {}
+            _ => set_clamped(encoder, "bitrate", kbps as i64),
+        }
+    }
+
+    pub fn set_keyframe_interval(self, encoder: &gst::Element, frames: u32) {
+        let frames = frames.max(1) as i64;
+        match self {
+            Self::VideoToolbox => set_clamped(encoder, "max-keyframe-interval", frames),
+            Self::Nvenc | Self::QuickSync => set_clamped(encoder, "gop-size", frames),
+            Self::Vaapi => set_clamped(encoder, "keyframe-period", frames),
+            Self::X264 => set_clamped(encoder, "key-int-max", frames),
+            Self::Vp8 | Self::Vp9 | Self::Aom => set_clamped(encoder, "keyframe-max-dist", frames),
+            Self::SvtAv1 => set_clamped(encoder, "intra-period-length", frames),
+            Self::Rav1e => set_clamped(encoder, "max-key-frame-interval", frames),
+            #[cfg(test)]
+            Self::FakeFailing | Self::Fake => {}
+        }
+    }
+
+    pub fn set_preset(self, encoder: &gst::Element, preset: EncoderPreset) {
+        match self {
+            // 0.0 - 1.0, higher is better
+            Self::VideoToolbox => encoder.set_property("quality", preset.pick(0.25, 0.5, 0.75)),
+            Self::Nvenc => encoder.set_property_from_str(
+                "preset",
+                preset.pick("low-latency-hp", "low-latency", "low-latency-hq"),
+            ),
+            // 1 (best quality) - 7 (fastest)
+            Self::Vaapi => encoder.set_property("quality-level", preset.pick(7u32, 4, 1)),
+            Self::QuickSync => encoder.set_property("target-usage", preset.pick(7u32, 4, 1)),
+            Self::X264 => encoder.set_property_from_str(
+                "speed-preset",
+                preset.pick("ultrafast", "superfast", "veryfast"),
+            ),
+            // Higher is faster for the rest
+            Self::Vp8 | Self::Vp9 => encoder.set_property("cpu-used", preset.pick(8i32, 6, 4)),
+            Self::SvtAv1 => encoder.set_property("preset", preset.pick(12u32, 10, 8)),
+            Self::Rav1e => encoder.set_property("speed-preset", preset.pick(10u32, 8, 6)),
+            Self::Aom => encoder.set_property("cpu-used", preset.pick(9i32, 8, 7)),
+            #[cfg(test)]
+            Self::FakeFailing | Self::Fake => {}
+        }
+    }
+}
+
+/// Set an integer property, clamped to the range the element accepts;
+/// out-of-range values would otherwise panic in `set_property`
+fn set_clamped(element: &gst::Element, name: &str, value: i64) {
+    let Some(pspec) = element.find_property(name) else {
+        tracing::warn!("{} has no property '{}'", element.name(), name);
+        return;
+    };
+    if let Some(p) = pspec.downcast_ref::<gst::glib::ParamSpecInt>() {
+        element.set_property(name, value.clamp(p.minimum() as i64, p.maximum() as i64) as i32);
+    } else if let Some(p) = pspec.downcast_ref::<gst::glib::ParamSpecUInt>() {
+        element.set_property(name, value.clamp(p.minimum() as i64, p.maximum() as i64) as u32);
+    } else if let Some(p) = pspec.downcast_ref::<gst::glib::ParamSpecInt64>() {
+        element.set_property(name, value.clamp(p.minimum(), p.maximum()));
+    } else if let Some(p) = pspec.downcast_ref::<gst::glib::ParamSpecUInt64>() {
+        let max = p.maximum().min(i64::MAX as u64) as i64;
+        element.set_property(name, value.clamp(p.minimum() as i64, max) as u64);
+    } else {
+        tracing::warn!("{}::{} is not an integer ({})", element.name(), name, pspec.value_type());
+    }
+}
+
+/// Which encoders may be used, from `ENCODER` / `ENCODER_EXCLUDE`
+#[derive(Debug, Clone, Default)]
+pub struct EncoderChoice {
+    /// Only these encoders, in this order
+    pub force: Option<Vec<EncoderKind>>,
+    pub exclude: Vec<EncoderKind>,
+}
+
+impl EncoderChoice {
+    /// Parse comma-separated factory names; unknown names are skipped with a warning
+    pub fn parse_list(list: &str) -> Vec<EncoderKind> {
+        list.split(',')
+            .filter(|name| !name.trim().is_empty())
+            .filter_map(|name| {
+                let kind = EncoderKind::from_name(name);
+                if kind.is_none() {
+                    tracing::warn!("Unknown encoder '{}'", name.trim());
+                }
+                kind
+            })
+            .collect()
+    }
+
+    /// Encoders to try, in order, skipping those that recently failed
+    pub fn kinds(&self) -> Vec<EncoderKind> {
+        let mut failed = FAILED_ENCODERS.lock().unwrap();
+        failed.retain(|(_, at)| at.elapsed() < FAILURE_TTL);
+        self.force
+            .as_deref()
+            .unwrap_or(&EncoderKind::ALL)
+            .iter()
+            .copied()
+            .filter(|k| !self.exclude.contains(k) && !failed.iter().any(|(kind, _)| kind == k))
+            .collect()
+    }
+}
+
+/// The encoder failed once the pipeline started
+#[derive(Debug)]
+pub struct EncoderFailed {
+    pub kind: EncoderKind,
+    pub reason: String,
+}
+
+impl std::fmt::Display for EncoderFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed: {}", self.kind.factory_name(), self.reason)
+    }
+}
+
+impl std::error::Error for EncoderFailed {}
+
+/// A running encoder and its current settings
+pub struct EncoderControl {
+    element: gst::Element,
+    kind: EncoderKind,
+    settings: Mutex<EncoderSettings>,
+    /// Bitrate requested by a client, not yet picked up by congestion control
+    requested_bitrate: Mutex<Option<u32>>,
+}
+
+impl EncoderControl {
+    /// Create the most preferred available encoder for the first codec
+    /// that has one (limited to 4:4:4 encoders if `settings.chroma_444`)
+    pub fn create(codecs: &[Codec], choice: &EncoderChoice, settings: EncoderSettings) -> Result<Self> {
+        for &codec in codecs {
+            let kinds = choice.kinds().into_iter().filter(|k| k.codec() == codec);
+            for kind in kinds.filter(|k| !settings.chroma_444 || k.supports_444()) {
+                match kind.build(&settings) {
+                    Ok(element) => {
+                        tracing::info!("Using {}", kind.description());
+                        return Ok(Self {
+                            element,
+                            kind,
+                            settings: Mutex::new(settings),
+                            requested_bitrate: Mutex::new(None),
+                        });
+                    }
+                    Err(e) => tracing::debug!("{} unavailable: {}", kind.factory_name(), e),
+                }
+            }
+            tracing::warn!("No {} encoder available", codec.encoding_name());
+        }
+
+        anyhow::bail!("No video encoder available for {:?}", codecs)
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.kind.codec()
+    }
+
+    pub fn element(&self) -> &gst::Element {
+        &self.element
+    }
+
+    pub fn settings(&self) -> EncoderSettings {
+        *self.settings.lock().unwrap()
+    }
+
+    /// Apply a client's settings change, with the bitrate kept within
+    /// `bitrate_range` (kbit/s). Frame rate is not an encoder property; the
+    /// caller applies it to the pipeline caps.
+    pub fn update(&self, update: &EncoderUpdate, bitrate_range: RangeInclusive<u32>) -> EncoderSettings {
+        let mut settings = self.settings.lock().unwrap();
+
+        if let Some(bitrate) = update.bitrate.filter(|&b| b > 0) {
+            let bitrate = bitrate.clamp(*bitrate_range.start(), *bitrate_range.end());
+            settings.bitrate = bitrate;
+            self.kind.set_bitrate(&self.element, bitrate);
+            *self.requested_bitrate.lock().unwrap() = Some(bitrate);
+        }
+        if let Some(fps) = update.fps.filter(|&f| f > 0) {
+            settings.fps = fps.min(MAX_FPS);
+        }
+        if let Some(interval) = update.keyframe_interval {
+            settings.keyframe_interval = interval.max(1);
+            self.kind.set_keyframe_interval(&self.element, settings.keyframe_interval);
+        }
+        if let Some(preset) = update.preset {
+            settings.preset = preset;
+            self.kind.set_preset(&self.element, preset);
+        }
+
+        tracing::info!("Encoder settings: {:?}", *settings);
+        *settings
+    }
+
+    /// Change the bitrate without touching the configured target, for
+    /// congestion control
+    pub fn set_current_bitrate(&self, kbps: u32) {
+        self.kind.set_bitrate(&self.element, kbps);
+    }
+
+    /// Take the bitrate last requested by a client, if any
+    pub fn take_requested_bitrate(&self) -> Option<u32> {
+        self.requested_bitrate.lock().unwrap().take()
+    }
+}
+
+/// Wait until every encoder produces its first output, failing with
+/// [`EncoderFailed`] for the encoder that posts an error first. The encoders
+/// share `pipeline`'s bus, so errors are attributed by their source. Blocks;
+/// other errors on the bus are returned as they are.
+pub fn await_startup(pipeline: &gst::Pipeline, encoders: &[&EncoderControl]) -> Result<()> {
+    let started: Vec<_> = encoders.iter().map(|_| Arc::new(AtomicBool::new(false))).collect();
+    let probes: Vec<_> = encoders
+        .iter()
+        .zip(&started)
+        .filter_map(|(encoder, started)| {
+            let pad = encoder.element().static_pad("src")?;
+            let started = started.clone();
+            let id = pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                started.store(true, Ordering::Relaxed);
+                gst::PadProbeReturn::Ok
+            })?;
+            Some((pad, id))
+        })
+        .collect();
+
+    let bus = pipeline.bus().expect("pipeline without bus");
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    let result = loop {
+        if started.iter().all(|s| s.load(Ordering::Relaxed)) || Instant::now() >= deadline {
+            break Ok(());
+        }
+
+        let Some(msg) = bus.timed_pop_filtered(
+            gst::ClockTime::from_mseconds(100),
+            &[gst::MessageType::Error],
+        ) else {
+            continue;
+        };
+        let gst::MessageView::Error(err) = msg.view() else { continue };
+
+        let failed = msg.src().and_then(|src| {
+            encoders.iter().find(|encoder| {
+                let element = encoder.element();
+                src == element.upcast_ref::<gst::Object>() || src.has_as_ancestor(element)
+            })
+        });
+        break Err(match failed {
+            Some(encoder) => {
+                let mut failed = FAILED_ENCODERS.lock().unwrap();
+                failed.retain(|(kind, _)| *kind != encoder.kind);
+                failed.push((encoder.kind, Instant::now()));
+                anyhow::Error::new(EncoderFailed {
+                    kind: encoder.kind,
+                    reason: err.error().to_string(),
+                })
+            }
+            None => anyhow::anyhow!("Pipeline error from {:?}: {}", msg.src().map(|s| s.path_string()), err.error()),
+        });
+    };
+
+    for (pad, id) in probes {
+        pad.remove_probe(id);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> EncoderSettings {
+        EncoderSettings {
+            bitrate: 1000,
+            fps: 30,
+            keyframe_interval: 30,
+            preset: EncoderPreset::Fast,
+            profile: QualityProfile::Standard,
+            chroma_444: false,
+        }
+    }
+
+    /// `videotestsrc ! encoder ! fakesink`, playing
+    fn start(encoder: &EncoderControl) -> gst::Pipeline {
+        let pipeline = gst::Pipeline::new();
+        let src = gst::ElementFactory::make("videotestsrc").build().unwrap();
+        let sink = gst::ElementFactory::make("fakesink").build().unwrap();
+        pipeline.add_many([&src, encoder.element(), &sink]).unwrap();
+        gst::Element::link_many([&src, encoder.element(), &sink]).unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        pipeline
+    }
+
+    /// Tests that record failures of the fake encoders run one at a time
+    static FAKE_ENCODERS: Mutex<()> = Mutex::new(());
+
+    fn forget_fake_failures() {
+        FAILED_ENCODERS
+            .lock()
+            .unwrap()
+            .retain(|(kind, _)| !matches!(kind, EncoderKind::Fake | EncoderKind::FakeFailing));
+    }
+
+    #[test]
+    fn failed_encoder_falls_back_to_next_kind() {
+        let _serial = FAKE_ENCODERS.lock().unwrap_or_else(|e| e.into_inner());
+        forget_fake_failures();
+        gst::init().unwrap();
+        let choice = EncoderChoice {
+            force: Some(vec![EncoderKind::FakeFailing, EncoderKind::Fake]),
+            exclude: Vec::new(),
+        };
+
+        let encoder = EncoderControl::create(&[Codec::H264], &choice, settings()).unwrap();
+        assert_eq!(encoder.kind, EncoderKind::FakeFailing);
+        let pipeline = start(&encoder);
+        let err = await_startup(&pipeline, &[&encoder]).unwrap_err();
+        assert_eq!(err.downcast_ref::<EncoderFailed>().unwrap().kind, EncoderKind::FakeFailing);
+        pipeline.set_state(gst::State::Null).unwrap();
+
+        let encoder = EncoderControl::create(&[Codec::H264], &choice, settings()).unwrap();
+        assert_eq!(encoder.kind, EncoderKind::Fake);
+        let pipeline = start(&encoder);
+        await_startup(&pipeline, &[&encoder]).unwrap();
+        pipeline.set_state(gst::State::Null).unwrap();
+    }
+
+    #[test]
+    fn shared_bus_error_is_attributed_to_its_encoder() {
+        let _serial = FAKE_ENCODERS.lock().unwrap_or_else(|e| e.into_inner());
+        forget_fake_failures();
+        gst::init().unwrap();
+        let create = |kind| {
+            let choice = EncoderChoice { force: Some(vec![kind]), exclude: Vec::new() };
+            EncoderControl::create(&[Codec::H264], &choice, settings()).unwrap()
+        };
+        let working = create(EncoderKind::Fake);
+        let failing = create(EncoderKind::FakeFailing);
+
+        // videotestsrc ! tee ! (queue ! working, queue ! failing) ! fakesink
+        let pipeline = gst::Pipeline::new();
+        let src = gst::ElementFactory::make("videotestsrc").build().unwrap();
+        let tee = gst::ElementFactory::make("tee").build().unwrap();
+        pipeline.add_many([&src, &tee]).unwrap();
+        src.link(&tee).unwrap();
+        for encoder in [&working, &failing] {
+            let queue = gst::ElementFactory::make("queue").build().unwrap();
+            let sink = gst::ElementFactory::make("fakesink").build().unwrap();
+            pipeline.add_many([&queue, encoder.element(), &sink]).unwrap();
+            gst::Element::link_many([&tee, &queue, encoder.element(), &sink]).unwrap();
+        }
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        // The failing encoder is listed last but still blamed
+        let err = await_startup(&pipeline, &[&working, &failing]).unwrap_err();
+        assert_eq!(err.downcast_ref::<EncoderFailed>().unwrap().kind, EncoderKind::FakeFailing);
+        pipeline.set_state(gst::State::Null).unwrap();
+        forget_fake_failures();
+    }
+
+    #[test]
+    fn failures_expire() {
+        FAILED_ENCODERS.lock().unwrap().push((EncoderKind::Vp8, Instant::now() - FAILURE_TTL));
+        let choice = EncoderChoice {
+            force: Some(vec![EncoderKind::Vp8]),
+            exclude: Vec::new(),
+        };
+        assert_eq!(choice.kinds(), vec![EncoderKind::Vp8]);
+    }
+}
@@ -0,0 +1,98 @@
+//! Static-content frame skipping
+//!
+//! Office desktops are unchanged most of the time, yet capture delivers
+//! full-rate frames that all get encoded. In damage mode each frame is
+//! hashed before the encoder and dropped if nothing changed, except for a
+//! low keep-alive rate so late joiners and lost keyframes recover. The first
+//! changed frame goes straight through, so motion is back at full rate
+//! immediately.
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+struct SkipState {
+    /// Hash of the last frame passed to the encoder
+    last_hash: Option<u64>,
+    /// When the last frame was passed to the encoder
+    last_sent: Option<Instant>,
+    /// Consecutive unchanged frames dropped
+    skipped: u64,
+}
+
+/// Drops frames identical to the previous one
+struct FrameSkipper {
+    keepalive: Duration,
+    state: Mutex<SkipState>,
+}
+
+impl FrameSkipper {
+    /// Whether a frame with this content should be encoded
+    fn admit(&self, hash: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let changed = state.last_hash != Some(hash);
+        let keepalive_due = state
+            .last_sent
+            .map_or(true, |last| now.duration_since(last) >= self.keepalive);
+        if !changed && !keepalive_due {
+            if state.skipped == 0 {
+                tracing::debug!("Screen idle, dropping to keep-alive rate");
+            }
+            state.skipped += 1;
+            return false;
+        }
+
+        if changed && state.skipped > 0 {
+            tracing::debug!("Screen changed after {} skipped frames", state.skipped);
+            state.skipped = 0;
+        }
+        state.last_hash = Some(hash);
+        state.last_sent = Some(now);
+        true
+    }
+}
+
+/// Drop unchanged raw video frames flowing through `pad`, still passing
+/// `idle_fps` frames per second while the picture is static
+pub fn install(pad: &gst::Pad, idle_fps: f64) {
+    let skipper = FrameSkipper {
+        keepalive: Duration::from_secs_f64(1.0 / idle_fps.max(0.1)),
+        state: Mutex::new(SkipState::default()),
+    };
+
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        let Some(gst::PadProbeData::Buffer(buffer)) = &info.data else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let Ok(map) = buffer.map_readable() else {
+            return gst::PadProbeReturn::Ok;
+        };
+
+        if skipper.admit(frame_hash(map.as_slice())) {
+            gst::PadProbeReturn::Ok
+        } else {
+            gst::PadProbeReturn::Drop
+        }
+    });
+}
+
+/// Fast non-cryptographic hash over the whole frame, so even a blinking
+/// cursor counts as a change
+fn frame_hash(data: &[u8]) -> u64 {
+    const K: u64 = 0x517c_c1b7_2722_0a95;
+
+    let mut chunks = data.chunks_exact(8);
+    let mut hash = data.len() as u64;
+    for chunk in &mut chunks {
+        let word = u64::from_ne_bytes(chunk.try_into().unwrap());
+        hash = (hash.rotate_left(5) ^ word).wrapping_mul(K);
+    }
+    for &byte in chunks.remainder() {
+        hash = (hash.rotate_left(5) ^ byte as u64).wrapping_mul(K);
+    }
+    hash
+}
@@ -0,0 +1,476 @@
+//! STUN/TURN servers and ICE transport policy
+//!
+//! Both ends use the same servers: `webrtcbin` is configured from them and
+//! the list is sent to the browser before the offer. TURN credentials are
+//! either static or issued per session from a shared secret, using the
+//! time-limited scheme of the TURN REST API (coturn's `use-auth-secret`).
+//! `ICE_LAN_ONLY` drops all servers so streamio works fully offline with
+//! host candidates.
+//!
+//! For firewalled hosts the nice agent can be limited to some interfaces
+//! and a UDP port range, IPv6 and TCP candidates can be dropped, and host
+//! candidates can announce a public address for NAT 1:1 mappings.
+
+use crate::config::{env_flag, env_parse, env_string};
+use anyhow::{Context, Result};
+use base64::Engine;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_STUN_SERVER: &str = "stun:stun.l.google.com:19302";
+const DEFAULT_TURN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// User part of REST credentials, after the expiry timestamp
+const TURN_REST_USER: &str = "streamio";
+
+/// Which candidates ICE may use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IceTransportPolicy {
+    #[default]
+    All,
+    /// Only TURN relay candidates, hiding both hosts' addresses
+    Relay,
+}
+
+impl FromStr for IceTransportPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "all" => Ok(Self::All),
+            "relay" => Ok(Self::Relay),
+            _ => anyhow::bail!("Unknown ICE transport policy '{}' (expected all or relay)", s),
+        }
+    }
+}
+
+/// How TURN credentials are obtained
+#[derive(Debug, Clone)]
+pub enum TurnAuth {
+    Static { username: String, password: String },
+    /// Time-limited credentials derived from a secret shared with the TURN server
+    Rest { secret: String, ttl: Duration },
+}
+
+/// A server entry in the browser's `RTCIceServer` format
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// ICE settings
+#[derive(Debug, Clone)]
+pub struct IceConfig {
+    /// `stun:host:port` URLs
+    pub stun_servers: Vec<String>,
+    /// `turn:host:port` / `turns:host:port` URLs, optionally with `?transport=`
+    pub turn_servers: Vec<String>,
+    pub turn_auth: Option<TurnAuth>,
+    pub policy: IceTransportPolicy,
+    /// Local addresses candidates are gathered on (all when empty)
+    pub local_addresses: Vec<IpAddr>,
+    /// Inclusive UDP port range for candidates
+    pub port_range: Option<(u16, u16)>,
+    pub ipv6: bool,
+    pub tcp: bool,
+    /// Address host candidates announce instead of their own (NAT 1:1)
+    pub public_ip: Option<IpAddr>,
+}
+
+impl Default for IceConfig {
+    fn default() -> Self {
+        Self {
+            stun_servers: Vec::new(),
+            turn_servers: Vec::new(),
+            turn_auth: None,
+            policy: IceTransportPolicy::All,
+            local_addresses: Vec::new(),
+            port_range: None,
+            ipv6: true,
+            tcp: true,
+            public_ip: None,
+        }
+    }
+}
+
+impl IceConfig {
+    /// Read `STUN_SERVERS`, `TURN_SERVERS`, `TURN_USERNAME` / `TURN_PASSWORD`
+    /// or `TURN_SECRET` / `TURN_TTL`, `ICE_TRANSPORT_POLICY` and `ICE_LAN_ONLY`
+    pub fn from_env() -> Result<Self> {
+        let network = Self::network_from_env()?;
+        let policy = match env_string("ICE_TRANSPORT_POLICY") {
+            Some(s) => s.parse()?,
+            None => IceTransportPolicy::All,
+        };
+
+        if env_flag("ICE_LAN_ONLY") {
+            if policy == IceTransportPolicy::Relay {
+                anyhow::bail!("ICE_TRANSPORT_POLICY=relay needs TURN servers, which ICE_LAN_ONLY disables");
+            }
+            tracing::info!("LAN-only ICE: no STUN or TURN servers");
+            return Ok(network);
+        }
+
+        let stun_servers = match env_string("STUN_SERVERS") {
+            Some(s) if s.eq_ignore_ascii_case("none") => Vec::new(),
+            Some(s) => parse_urls(&s, &["stun", "stuns"])?,
+            None => vec![DEFAULT_STUN_SERVER.to_string()],
+        };
+        let turn_servers = env_string("TURN_SERVERS")
+            .map(|s| parse_urls(&s, &["turn", "turns"]))
+            .transpose()?
+            .unwrap_or_default();
+
+        let turn_auth = if let Some(secret) = env_string("TURN_SECRET") {
+            let ttl = env_parse("TURN_TTL").map(Duration::from_secs).unwrap_or(DEFAULT_TURN_TTL);
+            Some(TurnAuth::Rest { secret, ttl })
+        } else {
+            match (env_string("TURN_USERNAME"), env_string("TURN_PASSWORD")) {
+                (Some(username), Some(password)) => Some(TurnAuth::Static { username, password }),
+                _ => None,
+            }
+        };
+
+        if !turn_servers.is_empty() && turn_auth.is_none() {
+            anyhow::bail!("TURN_SERVERS requires TURN_SECRET or TURN_USERNAME and TURN_PASSWORD");
+        }
+        if policy == IceTransportPolicy::Relay && turn_servers.is_empty() {
+            anyhow::bail!("ICE_TRANSPORT_POLICY=relay requires TURN_SERVERS");
+        }
+
+        Ok(Self {
+            stun_servers,
+            turn_servers,
+            turn_auth,
+            policy,
+            ..network
+        })
+    }
+
+    /// Read `ICE_INTERFACES`, `ICE_PORT_RANGE`, `ICE_DISABLE_IPV6`,
+    /// `ICE_DISABLE_TCP` and `ICE_PUBLIC_IP`
+    fn network_from_env() -> Result<Self> {
+        let mut local_addresses = Vec::new();
+        for interface in env_string("ICE_INTERFACES").iter().flat_map(|s| s.split(',')) {
+            let interface = interface.trim();
+            let addresses = match interface.parse::<IpAddr>() {
+                Ok(address) => vec![address],
+                Err(_) => interface_addresses(Some(interface)),
+            };
+            if addresses.is_empty() {
+                anyhow::bail!("ICE_INTERFACES: no address found for '{}'", interface);
+            }
+            local_addresses.extend(addresses);
+        }
+
+        let port_range = match env_string("ICE_PORT_RANGE") {
+            Some(s) => {
+                let range = s
+                    .split_once('-')
+                    .and_then(|(min, max)| Some((min.trim().parse::<u16>().ok()?, max.trim().parse::<u16>().ok()?)))
+                    .filter(|(min, max)| *min > 0 && min <= max);
+                Some(range.with_context(|| format!("Invalid ICE_PORT_RANGE '{}' (expected MIN-MAX)", s))?)
+            }
+            None => None,
+        };
+
+        let public_ip = match env_string("ICE_PUBLIC_IP") {
+            Some(s) => Some(s.parse().with_context(|| format!("Invalid ICE_PUBLIC_IP '{}'", s))?),
+            None => None,
+        };
+
+        let ipv6 = !env_flag("ICE_DISABLE_IPV6");
+        local_addresses.retain(|a| ipv6 || a.is_ipv4());
+        // The nice agent has no IPv6 switch, so it is kept off IPv6 by
+        // gathering on the IPv4 addresses only
+        if !ipv6 && local_addresses.is_empty() {
+            local_addresses = interface_addresses(None)
+                .into_iter()
+                .filter(|a| a.is_ipv4() && !a.is_loopback())
+                .collect();
+        }
+
+        Ok(Self {
+            local_addresses,
+            port_range,
+            ipv6,
+            tcp: !env_flag("ICE_DISABLE_TCP"),
+            public_ip,
+            ..Self::default()
+        })
+    }
+
+    /// Server list for one session, with fresh TURN credentials
+    pub fn servers(&self) -> Vec<IceServer> {
+        let mut servers = Vec::new();
+        if !self.stun_servers.is_empty() {
+            servers.push(IceServer {
+                urls: self.stun_servers.clone(),
+                username: None,
+                credential: None,
+            });
+        }
+        if let (false, Some(auth)) = (self.turn_servers.is_empty(), &self.turn_auth) {
+            let (username, credential) = match auth {
+                TurnAuth::Static { username, password } => (username.clone(), password.clone()),
+                TurnAuth::Rest { secret, ttl } => rest_credentials(secret, *ttl),
+            };
+            servers.push(IceServer {
+                urls: self.turn_servers.clone(),
+                username: Some(username),
+                credential: Some(credential),
+            });
+        }
+        servers
+    }
+
+    /// Point `webrtcbin` at the same servers the browser gets
+    pub fn configure(&self, webrtcbin: &gst::Element, servers: &[IceServer]) {
+        let mut stun = servers
+            .iter()
+            .filter(|s| s.username.is_none())
+            .flat_map(|s| &s.urls);
+        if let Some(url) = stun.next() {
+            webrtcbin.set_property("stun-server", gst_uri(url, None));
+        }
+        if stun.next().is_some() {
+            tracing::debug!("webrtcbin uses only the first STUN server");
+        }
+
+        for server in servers.iter().filter(|s| s.username.is_some()) {
+            let auth = server.username.as_deref().zip(server.credential.as_deref());
+            for url in &server.urls {
+                let added = webrtcbin.emit_by_name::<bool>("add-turn-server", &[&gst_uri(url, auth)]);
+                if !added {
+                    tracing::warn!("webrtcbin rejected TURN server {}", url);
+                }
+            }
+        }
+
+        if self.policy == IceTransportPolicy::Relay {
+            webrtcbin.set_property_from_str("ice-transport-policy", "relay");
+        }
+
+        let agent = webrtcbin.property::<gst::Object>("ice-agent");
+        for address in &self.local_addresses {
+            if !agent.emit_by_name::<bool>("add-local-ip-address", &[&address.to_string()]) {
+                tracing::warn!("ICE agent rejected local address {}", address);
+            }
+        }
+        if let Some((min, max)) = self.port_range {
+            agent.set_property("min-rtp-port", min as u32);
+            agent.set_property("max-rtp-port", max as u32);
+        }
+        if !self.tcp && agent.has_property("ice-tcp", None) {
+            agent.set_property("ice-tcp", false);
+        }
+    }
+
+    /// A gathered candidate as it should be sent to the browser, `None` to
+    /// withhold it
+    pub fn local_candidate(&self, candidate: &str) -> Option<String> {
+        let Some(parsed) = Candidate::parse(candidate) else {
+            return Some(candidate.to_string());
+        };
+        if !self.allows(&parsed) {
+            return None;
+        }
+        if parsed.kind == "host" && !self.local_addresses.is_empty() && !self.local_addresses.contains(&parsed.address) {
+            return None;
+        }
+
+        // Only same-family candidates can stand for the public address
+        match self.public_ip {
+            Some(public_ip) if parsed.kind == "host" && public_ip.is_ipv4() == parsed.address.is_ipv4() => {
+                Some(parsed.with_address(public_ip))
+            }
+            _ => Some(candidate.to_string()),
+        }
+    }
+
+    /// Whether a candidate from the browser should be used
+    pub fn accepts_remote(&self, candidate: &str) -> bool {
+        Candidate::parse(candidate).map_or(true, |parsed| self.allows(&parsed))
+    }
+
+    fn allows(&self, candidate: &Candidate) -> bool {
+        (self.ipv6 || candidate.address.is_ipv4()) && (self.tcp || !candidate.transport.eq_ignore_ascii_case("tcp"))
+    }
+}
+
+/// The fields of an SDP `candidate:` attribute that filtering looks at
+struct Candidate<'a> {
+    fields: Vec<&'a str>,
+    transport: &'a str,
+    address: IpAddr,
+    /// `host`, `srflx`, `prflx` or `relay`
+    kind: &'a str,
+}
+
+impl<'a> Candidate<'a> {
+    /// Parse `candidate:FOUNDATION COMPONENT TRANSPORT PRIORITY ADDRESS PORT typ TYPE ...`.
+    /// mDNS (`.local`) addresses are not parsed.
+    fn parse(candidate: &'a str) -> Option<Self> {
+        let fields: Vec<&str> = candidate.split_whitespace().collect();
+        if fields.len() < 8 || fields[6] != "typ" {
+            return None;
+        }
+        Some(Self {
+            transport: fields[2],
+            address: fields[4].parse().ok()?,
+            kind: fields[7],
+            fields,
+        })
+    }
+
+    fn with_address(&self, address: IpAddr) -> String {
+        let mut fields = self.fields.clone();
+        let address = address.to_string();
+        fields[4] = &address;
+        fields.join(" ")
+    }
+}
+
+/// Addresses of a network interface by name, or of all interfaces
+#[cfg(unix)]
+fn interface_addresses(name: Option<&str>) -> Vec<IpAddr> {
+    use std::ffi::CStr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut addresses = Vec::new();
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs(3) allocates a list that is only read here and
+    // freed before returning; address pointers are cast per their family
+    unsafe {
+        if libc::getifaddrs(&mut list) != 0 {
+            return addresses;
+        }
+        let mut cursor = list;
+        while let Some(entry) = cursor.as_ref() {
+            cursor = entry.ifa_next;
+            if entry.ifa_addr.is_null() || name.is_some_and(|name| CStr::from_ptr(entry.ifa_name).to_str() != Ok(name)) {
+                continue;
+            }
+            match (*entry.ifa_addr).sa_family as i32 {
+                libc::AF_INET => {
+                    let addr = &*(entry.ifa_addr as *const libc::sockaddr_in);
+                    addresses.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))));
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(entry.ifa_addr as *const libc::sockaddr_in6);
+                    addresses.push(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+        libc::freeifaddrs(list);
+    }
+    addresses
+}
+
+#[cfg(not(unix))]
+fn interface_addresses(name: Option<&str>) -> Vec<IpAddr> {
+    if name.is_some() {
+        tracing::warn!("Interface names are only resolved on Unix; use IP addresses in ICE_INTERFACES");
+    }
+    Vec::new()
+}
+
+/// Split a comma-separated URL list, checking the schemes and normalizing
+/// `scheme://host` to the browser's `scheme:host`
+fn parse_urls(list: &str, schemes: &[&str]) -> Result<Vec<String>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(|url| match url.split_once(':') {
+            Some((scheme, rest)) if schemes.contains(&scheme.to_ascii_lowercase().as_str()) => {
+                Ok(format!("{}:{}", scheme.to_ascii_lowercase(), rest.trim_start_matches("//")))
+            }
+            _ => anyhow::bail!("Invalid ICE server URL '{}' (expected {}:host:port)", url, schemes[0]),
+        })
+        .collect()
+}
+
+/// Browser-style URL as the `scheme://[user:pass@]host:port` URI `webrtcbin` takes
+fn gst_uri(url: &str, auth: Option<(&str, &str)>) -> String {
+    let (scheme, rest) = url.split_once(':').unwrap_or(("stun", url));
+    match auth {
+        Some((username, password)) => format!(
+            "{}://{}:{}@{}",
+            scheme,
+            percent_encode(username),
+            percent_encode(password),
+            rest
+        ),
+        None => format!("{}://{}", scheme, rest),
+    }
+}
+
+/// Escape everything but unreserved characters, for URI user info
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// TURN REST API credentials: `expiry:user` signed with the shared secret
+fn rest_credentials(secret: &str, ttl: Duration) -> (String, String) {
+    let expiry = (SystemTime::now() + ttl)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let username = format!("{}:{}", expiry, TURN_REST_USER);
+    let signature = hmac_sha1(secret.as_bytes(), username.as_bytes());
+    let password = base64::engine::general_purpose::STANDARD.encode(signature);
+    (username, password)
+}
+
+/// HMAC-SHA1 (RFC 2104)
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// RFC 2202 test cases 1, 2, 3 and 6
+    #[test]
+    fn hmac_sha1_test_vectors() {
+        assert_eq!(
+            hex(&hmac_sha1(&[0x0b; 20], b"Hi There")),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+        assert_eq!(
+            hex(&hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        assert_eq!(
+            hex(&hmac_sha1(&[0xaa; 20], &[0xdd; 50])),
+            "125d7342b9ac11cd91a39af48aa17b4f63f175d3"
+        );
+        assert_eq!(
+            hex(&hmac_sha1(&[0xaa; 80], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+}
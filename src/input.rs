@@ -5,6 +5,7 @@
 use enigo::{Enigo, Keyboard, Mouse, Settings, Coordinate, Button, Direction};
 use serde::Deserialize;
 use std::sync::Mutex;
+use tokio::sync::mpsc;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -29,14 +30,71 @@ pub struct Modifiers {
     pub meta: bool,
 }
 
+/// Maps coordinates in the streamed video to screen coordinates
+#[derive(Debug, Clone, Copy)]
+pub struct InputTransform {
+    /// Screen position of the video's top-left corner
+    pub origin_x: i32,
+    pub origin_y: i32,
+    /// Screen pixels per video pixel
+    pub scale_x: f64,
+    pub scale_y: f64,
+}
+
+impl Default for InputTransform {
+    fn default() -> Self {
+        Self {
+            origin_x: 0,
+            origin_y: 0,
+            scale_x: 1.0,
+            scale_y: 1.0,
+        }
+    }
+}
+
+impl InputTransform {
+    pub fn apply(&self, x: i32, y: i32) -> (i32, i32) {
+        (
+            self.origin_x + (x as f64 * self.scale_x).round() as i32,
+            self.origin_y + (y as f64 * self.scale_y).round() as i32,
+        )
+    }
+}
+
+impl InputEvent {
+    /// Translate pointer coordinates with a transform
+    pub fn transform(self, t: &InputTransform) -> Self {
+        match self {
+            InputEvent::MouseDown { button, x, y } => {
+                let (x, y) = t.apply(x, y);
+                InputEvent::MouseDown { button, x, y }
+            }
+            InputEvent::MouseUp { button, x, y } => {
+                let (x, y) = t.apply(x, y);
+                InputEvent::MouseUp { button, x, y }
+            }
+            InputEvent::MouseMove { x, y } => {
+                let (x, y) = t.apply(x, y);
+                InputEvent::MouseMove { x, y }
+            }
+            other => other,
+        }
+    }
+}
+
 /// Input controller using enigo
 pub struct InputController {
     enigo: Mutex<Enigo>,
 }
 
 impl InputController {
-    pub fn new() -> Self {
-        let enigo = Enigo::new(&Settings::default()).expect("Failed to create Enigo");
+    /// Create a controller, optionally targeting a specific X display
+    pub fn new(display: Option<&str>) -> Self {
+        let settings = Settings {
+            x11_display: display.map(str::to_string),
+            ..Settings::default()
+        };
+        let enigo = Enigo::new(&settings).expect("Failed to create Enigo");
         Self {
             enigo: Mutex::new(enigo),
         }
@@ -123,6 +181,23 @@ impl InputController {
     }
 }
 
+/// Start an input handling thread for a display and return its sender.
+///
+/// The thread exits once every sender has been dropped.
+pub fn start_input_thread(display: Option<String>) -> mpsc::UnboundedSender<InputEvent> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<InputEvent>();
+
+    // Spawn a blocking thread for input handling (Enigo is not Send)
+    std::thread::spawn(move || {
+        let controller = InputController::new(display.as_deref());
+        while let Some(event) = rx.blocking_recv() {
+            controller.handle_event(&event);
+        }
+    });
+
+    tx
+}
+
 fn map_key(key: &str) -> Option<enigo::Key> {
     match key {
         "Enter" => Some(enigo::Key::Return),
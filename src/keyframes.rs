@@ -0,0 +1,102 @@
+//! Keyframe requests
+//!
+//! Viewers ask for a keyframe with RTCP PLI/FIR when they join or lose
+//! packets; the RTP session turns those into force-key-unit events that
+//! travel upstream to the encoder. Clients can also ask explicitly with a
+//! `refresh` message. Requests arriving faster than the minimum interval
+//! are collapsed into one keyframe at the end of the interval, so packet
+//! loss bursts don't turn into a stream of expensive keyframes.
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_video as gst_video;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    /// When the last request was passed to the encoder
+    last: Option<Instant>,
+    /// A collapsed request is waiting for the interval to pass
+    deferred: bool,
+}
+
+/// Rate-limits keyframe requests reaching an encoder
+pub struct KeyframeLimiter {
+    /// Encoder source pad, where upstream requests arrive
+    pad: gst::Pad,
+    min_interval: Duration,
+    state: Mutex<LimiterState>,
+}
+
+impl KeyframeLimiter {
+    /// Start filtering keyframe requests to `encoder`
+    pub fn install(encoder: &gst::Element, min_interval: Duration) -> Option<Arc<Self>> {
+        let pad = encoder.static_pad("src")?;
+        let limiter = Arc::new(Self {
+            pad: pad.clone(),
+            min_interval,
+            state: Mutex::new(LimiterState::default()),
+        });
+
+        let weak = Arc::downgrade(&limiter);
+        pad.add_probe(gst::PadProbeType::EVENT_UPSTREAM, move |_, info| {
+            let Some(gst::PadProbeData::Event(event)) = &info.data else {
+                return gst::PadProbeReturn::Ok;
+            };
+            if !gst_video::ForceKeyUnitEvent::is(event) {
+                return gst::PadProbeReturn::Ok;
+            }
+            match weak.upgrade() {
+                Some(limiter) if !limiter.admit() => gst::PadProbeReturn::Drop,
+                _ => gst::PadProbeReturn::Ok,
+            }
+        });
+
+        Some(limiter)
+    }
+
+    /// Ask the encoder for a keyframe, subject to the rate limit
+    pub fn request(&self) {
+        let event = gst_video::UpstreamForceKeyUnitEvent::builder()
+            .all_headers(true)
+            .build();
+
+        // Upstream events are pushed from the downstream peer so they pass our probe
+        match self.pad.peer() {
+            Some(peer) => {
+                peer.push_event(event);
+            }
+            None => tracing::debug!("Encoder not linked, dropping keyframe request"),
+        }
+    }
+
+    /// Whether a request may pass now; otherwise schedules one for later
+    fn admit(self: &Arc<Self>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let wait = state
+            .last
+            .map(|last| self.min_interval.saturating_sub(now - last))
+            .unwrap_or_default();
+        if wait.is_zero() {
+            state.last = Some(now);
+            state.deferred = false;
+            tracing::debug!("Forcing keyframe");
+            return true;
+        }
+
+        if !state.deferred {
+            state.deferred = true;
+            let limiter = Arc::downgrade(self);
+            std::thread::spawn(move || {
+                std::thread::sleep(wait);
+                if let Some(limiter) = limiter.upgrade() {
+                    limiter.request();
+                }
+            });
+        }
+        false
+    }
+}
@@ -0,0 +1,332 @@
+//! Glass-to-glass latency measurement
+//!
+//! With `LATENCY_PROBE=1` the server notes, for each outgoing video frame,
+//! its RTP timestamp, when it was captured (from its timestamp on the
+//! pipeline clock), when the encoder produced it and when its packets left
+//! for `webrtcbin`. The browser reports the RTP timestamp of frames it
+//! shows (`requestVideoFrameCallback`) with their receive and display
+//! times, and echoes periodic clock pings so its clock can be mapped onto
+//! the server's. Matching the two gives the time spent in each stage, which
+//! is logged, sent to the client and listed by the admin endpoint.
+
+use crate::screen_capture::SignalingMessage;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+const PING_INTERVAL: Duration = Duration::from_secs(2);
+/// Pings between reports
+const PINGS_PER_REPORT: u32 = 3;
+/// Frames remembered for matching client reports (~10 s at 30 fps)
+const FRAME_HISTORY: usize = 300;
+/// Clock samples kept; the one with the lowest round trip is used
+const CLOCK_SAMPLES: usize = 8;
+
+/// Average time per stage over a reporting interval, in milliseconds
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LatencyReport {
+    /// Frames the averages are over
+    pub frames: u32,
+    pub capture_to_encode_ms: Option<f64>,
+    pub encode_to_send_ms: Option<f64>,
+    pub network_ms: Option<f64>,
+    pub receive_to_display_ms: Option<f64>,
+    pub total_ms: f64,
+    /// Signaling round trip used for clock sync
+    pub clock_rtt_ms: f64,
+}
+
+/// Server-side times of one frame, in ms since the Unix epoch
+#[derive(Debug, Clone, Copy)]
+struct FrameTimes {
+    rtp_timestamp: u32,
+    captured: f64,
+    encoded: Option<f64>,
+    sent: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClockSample {
+    rtt: f64,
+    /// Client clock minus server clock
+    offset: f64,
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    frames: u32,
+    capture_to_encode: (f64, u32),
+    encode_to_send: (f64, u32),
+    network: (f64, u32),
+    receive_to_display: (f64, u32),
+    total: f64,
+}
+
+fn average((sum, count): (f64, u32)) -> Option<f64> {
+    (count > 0).then(|| sum / count as f64)
+}
+
+#[derive(Debug, Default)]
+struct ProbeState {
+    /// Encoder output times by buffer timestamp
+    encoded: VecDeque<(gst::ClockTime, f64)>,
+    frames: VecDeque<FrameTimes>,
+    clock: VecDeque<ClockSample>,
+    totals: Totals,
+    last_report: Option<LatencyReport>,
+}
+
+/// Per-session latency bookkeeping
+#[derive(Debug, Default)]
+pub struct LatencyProbe {
+    state: Mutex<ProbeState>,
+}
+
+fn now_ms() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64() * 1000.0
+}
+
+/// Wall-clock time of a buffer timestamp, from how long ago it was on the
+/// element's pipeline clock
+fn wall_time(element: &gst::Element, pts: gst::ClockTime) -> Option<f64> {
+    let clock = element.clock()?;
+    let stamped = element.base_time()? + pts;
+    let age = clock.time()?.saturating_sub(stamped);
+    Some(now_ms() - age.nseconds() as f64 / 1_000_000.0)
+}
+
+/// RTP timestamp of a packet
+fn rtp_timestamp(buffer: &gst::BufferRef) -> Option<u32> {
+    let map = buffer.map_readable().ok()?;
+    let data = map.as_slice();
+    (data.len() >= 12 && data[0] >> 6 == 2).then(|| u32::from_be_bytes([data[4], data[5], data[6], data[7]]))
+}
+
+impl LatencyProbe {
+    /// Watch frames leave `encoder` (when the session has its own) and
+    /// RTP packets pass `rtp_pad` on their way to `webrtcbin`
+    pub fn install(encoder: Option<&gst::Element>, rtp_pad: &gst::Pad) -> Arc<Self> {
+        let probe = Arc::new(Self::default());
+
+        if let Some(pad) = encoder.and_then(|e| e.static_pad("src")) {
+            let weak = Arc::downgrade(&probe);
+            pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+                if let (Some(probe), Some(gst::PadProbeData::Buffer(buffer))) = (weak.upgrade(), &info.data) {
+                    if let Some(pts) = buffer.pts() {
+                        let mut state = probe.state.lock().unwrap();
+                        state.encoded.push_back((pts, now_ms()));
+                        if state.encoded.len() > 64 {
+                            state.encoded.pop_front();
+                        }
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+
+        let weak = Arc::downgrade(&probe);
+        rtp_pad.add_probe(gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST, move |pad, info| {
+            let buffer = match &info.data {
+                Some(gst::PadProbeData::Buffer(buffer)) => Some(buffer.as_ref()),
+                Some(gst::PadProbeData::BufferList(list)) => list.get(0),
+                _ => None,
+            };
+            if let (Some(probe), Some(buffer), Some(element)) = (weak.upgrade(), buffer, pad.parent_element()) {
+                probe.packet_sent(&element, buffer);
+            }
+            gst::PadProbeReturn::Ok
+        });
+
+        probe
+    }
+
+    /// Record the first packet of each frame
+    fn packet_sent(&self, element: &gst::Element, buffer: &gst::BufferRef) {
+        let (Some(rtp_timestamp), Some(pts)) = (rtp_timestamp(buffer), buffer.pts()) else { return };
+        let mut state = self.state.lock().unwrap();
+        if state.frames.back().is_some_and(|f| f.rtp_timestamp == rtp_timestamp) {
+            return;
+        }
+        let Some(captured) = wall_time(element, pts) else { return };
+
+        let encoded = state.encoded.iter().find(|(p, _)| *p == pts).map(|(_, t)| *t);
+        state.frames.push_back(FrameTimes {
+            rtp_timestamp,
+            captured,
+            encoded,
+            sent: now_ms(),
+        });
+        if state.frames.len() > FRAME_HISTORY {
+            state.frames.pop_front();
+        }
+    }
+
+    /// Clock ping for the client to echo
+    pub fn ping(&self) -> SignalingMessage {
+        SignalingMessage::LatencyPing { server_time: now_ms() }
+    }
+
+    /// Client's echo of a ping, with its own clock at the time
+    pub fn pong(&self, server_time: f64, client_time: f64) {
+        let rtt = (now_ms() - server_time).max(0.0);
+        let mut state = self.state.lock().unwrap();
+        state.clock.push_back(ClockSample {
+            rtt,
+            offset: client_time - (server_time + rtt / 2.0),
+        });
+        if state.clock.len() > CLOCK_SAMPLES {
+            state.clock.pop_front();
+        }
+    }
+
+    /// Client showed a frame; times are on the client's clock
+    pub fn frame_displayed(&self, rtp_timestamp: u32, received_at: Option<f64>, displayed_at: f64) {
+        let mut state = self.state.lock().unwrap();
+        let Some(clock) = state.clock.iter().min_by(|a, b| a.rtt.total_cmp(&b.rtt)).copied() else { return };
+        let Some(frame) = state.frames.iter().find(|f| f.rtp_timestamp == rtp_timestamp).copied() else { return };
+
+        let displayed = displayed_at - clock.offset;
+        let received = received_at.map(|t| t - clock.offset);
+        let totals = &mut state.totals;
+        let add = |stage: &mut (f64, u32), ms: Option<f64>| {
+            if let Some(ms) = ms {
+                stage.0 += ms.max(0.0);
+                stage.1 += 1;
+            }
+        };
+        add(&mut totals.capture_to_encode, frame.encoded.map(|e| e - frame.captured));
+        add(&mut totals.encode_to_send, frame.encoded.map(|e| frame.sent - e));
+        add(&mut totals.network, received.map(|r| r - frame.sent));
+        add(&mut totals.receive_to_display, received.map(|r| displayed - r));
+        totals.total += (displayed - frame.captured).max(0.0);
+        totals.frames += 1;
+    }
+
+    /// Averages since the last report, if the client reported any frames
+    fn take_report(&self) -> Option<LatencyReport> {
+        let mut state = self.state.lock().unwrap();
+        let totals = std::mem::take(&mut state.totals);
+        if totals.frames == 0 {
+            return None;
+        }
+
+        let report = LatencyReport {
+            frames: totals.frames,
+            capture_to_encode_ms: average(totals.capture_to_encode),
+            encode_to_send_ms: average(totals.encode_to_send),
+            network_ms: average(totals.network),
+            receive_to_display_ms: average(totals.receive_to_display),
+            total_ms: totals.total / totals.frames as f64,
+            clock_rtt_ms: state.clock.iter().map(|c| c.rtt).fold(f64::INFINITY, f64::min),
+        };
+        state.last_report = Some(report);
+        Some(report)
+    }
+
+    /// Most recent report
+    pub fn last_report(&self) -> Option<LatencyReport> {
+        self.state.lock().unwrap().last_report
+    }
+}
+
+/// Ping the client periodically, and log and send a report every few pings
+pub fn spawn_reporter(
+    probe: Arc<LatencyProbe>,
+    outgoing_tx: mpsc::UnboundedSender<SignalingMessage>,
+) -> tokio::task::AbortHandle {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PING_INTERVAL);
+        let mut pings = 0;
+        loop {
+            interval.tick().await;
+            if outgoing_tx.send(probe.ping()).is_err() {
+                break;
+            }
+
+            pings += 1;
+            if pings % PINGS_PER_REPORT != 0 {
+                continue;
+            }
+            if let Some(report) = probe.take_report() {
+                tracing::info!(
+                    total_ms = format!("{:.0}", report.total_ms),
+                    capture_to_encode_ms = ?report.capture_to_encode_ms.map(|ms| ms.round()),
+                    encode_to_send_ms = ?report.encode_to_send_ms.map(|ms| ms.round()),
+                    network_ms = ?report.network_ms.map(|ms| ms.round()),
+                    receive_to_display_ms = ?report.receive_to_display_ms.map(|ms| ms.round()),
+                    frames = report.frames,
+                    "Glass-to-glass latency"
+                );
+                let _ = outgoing_tx.send(SignalingMessage::Latency { report });
+            }
+        }
+    })
+    .abort_handle()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Client clock ahead of the server's
+    const OFFSET: f64 = 5000.0;
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("stage missing");
+        assert!((actual - expected).abs() < 1.0, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn averages_stages_with_synced_clock() {
+        let probe = LatencyProbe::default();
+        {
+            let mut state = probe.state.lock().unwrap();
+            state.frames.push_back(FrameTimes {
+                rtp_timestamp: 1000,
+                captured: 100_000.0,
+                encoded: Some(100_010.0),
+                sent: 100_015.0,
+            });
+            state.frames.push_back(FrameTimes {
+                rtp_timestamp: 4000,
+                captured: 100_033.0,
+                encoded: None,
+                sent: 100_050.0,
+            });
+        }
+
+        // Reports before the clock is synced cannot be placed on the server's clock
+        probe.frame_displayed(1000, Some(OFFSET + 100_045.0), OFFSET + 100_053.0);
+        assert!(probe.take_report().is_none());
+
+        // A 20 ms round trip with the true offset, and a slow one that must be ignored
+        let sent = now_ms() - 20.0;
+        probe.pong(sent, sent + 10.0 + OFFSET);
+        let sent = now_ms() - 200.0;
+        probe.pong(sent, sent + 3000.0);
+
+        // Frame 1000: 30 ms on the network, shown 8 ms after arriving
+        probe.frame_displayed(1000, Some(OFFSET + 100_045.0), OFFSET + 100_053.0);
+        // Frame 4000: no encoder time, 20 ms network, 20 ms to display
+        probe.frame_displayed(4000, Some(OFFSET + 100_070.0), OFFSET + 100_090.0);
+        // Frames the server does not know are skipped
+        probe.frame_displayed(7000, None, OFFSET + 100_100.0);
+
+        let report = probe.take_report().unwrap();
+        assert_eq!(report.frames, 2);
+        assert_close(report.capture_to_encode_ms, 10.0);
+        assert_close(report.encode_to_send_ms, 5.0);
+        assert_close(report.network_ms, 25.0);
+        assert_close(report.receive_to_display_ms, 14.0);
+        assert_close(Some(report.total_ms), 55.0);
+        assert_close(Some(report.clock_rtt_ms), 20.0);
+
+        // Totals start over after each report
+        assert!(probe.take_report().is_none());
+        assert_eq!(probe.last_report().unwrap().frames, 2);
+    }
+}
@@ -2,11 +2,36 @@
 //!
 //! Captures the screen/display and streams via WebRTC to browsers.
 
+mod admin;
+mod app_session;
+mod auth;
+mod bus_monitor;
+mod config;
+mod congestion;
+mod cursor;
+mod desktop;
+mod display_mode;
+mod encoder;
+mod frame_skip;
+mod ice;
 mod input;
+mod keyframes;
+mod latency;
+mod media_source;
+mod monitors;
+mod process;
+mod redaction;
 mod screen_capture;
 mod screen_server;
+mod sessions;
+mod simulcast;
+mod video_scaler;
+mod virtual_display;
+mod watermark;
+mod window_capture;
 
 use anyhow::Result;
+use screen_capture::StreamConfig;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 const DEFAULT_FPS: u32 = 30;
@@ -90,11 +115,107 @@ async fn main() -> Result<()> {
         .unwrap_or(DEFAULT_FPS);
 
     tracing::info!("Streamio v{}", env!("CARGO_PKG_VERSION"));
+
+    // Application mode: every session launches APP_COMMAND on its own display
+    let app = app_session::AppConfig::from_env();
+    if let Some(app) = &app {
+        tracing::info!("Application mode: streaming '{}'", app.command);
+    }
+
+    // Per-user desktop mode: every authenticated user gets a private display
+    let desktops = desktop::DesktopConfig::from_env();
+    let auth = auth::Auth::from_env();
+    if let Some(desktops) = &desktops {
+        if app.is_some() {
+            anyhow::bail!("APP_COMMAND and PER_USER_DESKTOPS cannot be combined");
+        }
+        if !auth.enabled() {
+            anyhow::bail!("PER_USER_DESKTOPS requires USERS to be configured");
+        }
+        tracing::info!(
+            "Per-user desktop mode: up to {} desktops, kept {:?} after disconnect",
+            desktops.max_desktops, desktops.keepalive
+        );
+    }
+
+    // Headless mode: run our own virtual display for capture and input.
+    // Kept alive until the server shuts down, then dropped to clean it up.
+    let virtual_display = if app.is_none() && desktops.is_none() && config::env_flag("HEADLESS") {
+        let vd_config = virtual_display::VirtualDisplayConfig::from_env();
+        Some(virtual_display::VirtualDisplay::spawn(&vd_config)?)
+    } else {
+        None
+    };
+
+    let stream = StreamConfig {
+        fps,
+        display: virtual_display.as_ref().map(|vd| vd.name().to_string()),
+        monitor: config::env_string("MONITOR"),
+        region: config::env_string("CAPTURE_REGION")
+            .and_then(|s| screen_capture::CaptureRegion::parse(&s)),
+        window: config::env_string("CAPTURE_WINDOW")
+            .and_then(|s| window_capture::WindowSelector::parse(&s)),
+        max_resolution: config::env_string("MAX_RESOLUTION")
+            .and_then(|s| config::parse_resolution(&s)),
+        resize_display: config::env_flag("RESIZE_DISPLAY"),
+        congestion: congestion::CongestionConfig::from_env(),
+        keyframe_interval: config::env_parse("KEYFRAME_INTERVAL").unwrap_or(30),
+        preset: config::env_parse("ENCODER_PRESET").unwrap_or_default(),
+        keyframe_min_interval: std::time::Duration::from_millis(
+            config::env_parse("KEYFRAME_MIN_INTERVAL").unwrap_or(1000),
+        ),
+        codecs: config::env_string("CODECS")
+            .map(|s| s.split(',').filter_map(|c| c.parse().ok()).collect())
+            .unwrap_or_else(|| encoder::Codec::DEFAULT_PREFERENCE.to_vec()),
+        client_codecs: None,
+        client_codecs_444: Vec::new(),
+        profile: match config::env_string("QUALITY_PROFILE") {
+            Some(s) => s.parse()?,
+            None => encoder::QualityProfile::default(),
+        },
+        encoders: encoder::EncoderChoice {
+            force: config::env_string("ENCODER").map(|s| encoder::EncoderChoice::parse_list(&s)),
+            exclude: config::env_string("ENCODER_EXCLUDE")
+                .map(|s| encoder::EncoderChoice::parse_list(&s))
+                .unwrap_or_default(),
+        },
+        idle_fps: config::env_flag("DAMAGE_CAPTURE")
+            .then(|| config::env_parse("IDLE_FPS").unwrap_or(1.0)),
+        source: media_source::CaptureSource::from_env()?,
+        ice: ice::IceConfig::from_env()?,
+        auto_restart: config::env_flag("AUTO_RESTART"),
+        simulate_error_after: config::env_parse("SIMULATE_ERROR_AFTER"),
+        latency_probe: config::env_flag("LATENCY_PROBE"),
+        client_cursor: config::env_flag("CLIENT_CURSOR"),
+        redactions: redaction::Redactions::from_env()?,
+        watermark: watermark::WatermarkConfig::from_env()?,
+        viewer: Default::default(),
+    };
+
     tracing::info!("Capturing screen at {} fps", fps);
     tracing::info!("Open http://localhost:{} to view", port);
 
+    let redactions = std::sync::Mutex::new(stream.redactions.clone());
+    let state = screen_server::AppState {
+        stream,
+        app,
+        desktops: desktops.map(desktop::DesktopManager::new),
+        simulcast: config::env_flag("SIMULCAST").then(|| {
+            simulcast::SimulcastShare::new(
+                config::env_string("SIMULCAST_LAYERS")
+                    .and_then(|s| simulcast::LayerConfig::parse_list(&s))
+                    .unwrap_or_else(simulcast::LayerConfig::default_layers),
+            )
+        }),
+        auth,
+        sessions: Default::default(),
+        redactions,
+    };
+
     // Start server
-    screen_server::run_server(fps, port).await?;
+    screen_server::run_server(state, port).await?;
+
+    drop(virtual_display);
 
     Ok(())
 }
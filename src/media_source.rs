@@ -0,0 +1,244 @@
+//! Synthetic and file capture sources
+//!
+//! Besides the platform screen capture, sessions can stream a test pattern
+//! (`videotestsrc` / `audiotestsrc`) or a media file decoded with
+//! `uridecodebin`, which lets streamio run in CI and on machines without a
+//! display.
+
+use crate::config::{env_flag, env_string, parse_resolution};
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const DEFAULT_TEST_PATTERN: &str = "smpte";
+const DEFAULT_TEST_RESOLUTION: (u32, u32) = (1280, 720);
+
+/// Where video (and audio) comes from
+#[derive(Debug, Clone, Default)]
+pub enum CaptureSource {
+    /// Screen capture for the platform (ximagesrc, avfvideosrc, ...)
+    #[default]
+    Platform,
+    /// Generated test signal
+    TestPattern {
+        /// `videotestsrc` pattern name, e.g. `smpte`, `ball`, `snow`
+        pattern: String,
+        width: u32,
+        height: u32,
+        /// `audiotestsrc` wave name, e.g. `sine`, `ticks`
+        wave: String,
+    },
+    /// Video track of a media file or stream
+    File { uri: String, looped: bool },
+}
+
+impl CaptureSource {
+    /// Read `CAPTURE_SOURCE` (`platform`, `test-pattern` or `file`) and its settings
+    pub fn from_env() -> Result<Self> {
+        match env_string("CAPTURE_SOURCE").as_deref() {
+            None | Some("platform") => Ok(Self::Platform),
+            Some("test-pattern") => {
+                let (width, height) = env_string("TEST_RESOLUTION")
+                    .and_then(|s| parse_resolution(&s))
+                    .unwrap_or(DEFAULT_TEST_RESOLUTION);
+                let pattern = env_string("TEST_PATTERN").unwrap_or_else(|| DEFAULT_TEST_PATTERN.into());
+                let wave = env_string("TEST_AUDIO_WAVE").unwrap_or_else(|| "sine".into());
+                set_enum(&gst::ElementFactory::make("videotestsrc").build()?, "pattern", &pattern)
+                    .context("Invalid TEST_PATTERN")?;
+                set_enum(&gst::ElementFactory::make("audiotestsrc").build()?, "wave", &wave)
+                    .context("Invalid TEST_AUDIO_WAVE")?;
+                Ok(Self::TestPattern { pattern, width, height, wave })
+            }
+            Some("file") => {
+                let location = env_string("FILE_URI").context("CAPTURE_SOURCE=file requires FILE_URI")?;
+                Ok(Self::File {
+                    uri: to_uri(&location)?,
+                    looped: env_flag("FILE_LOOP"),
+                })
+            }
+            Some(other) => anyhow::bail!(
+                "Unknown CAPTURE_SOURCE '{}' (expected platform, test-pattern or file)",
+                other
+            ),
+        }
+    }
+}
+
+/// Accept URIs as they are and turn file paths into `file://` URIs
+fn to_uri(location: &str) -> Result<String> {
+    if location.contains("://") {
+        return Ok(location.to_string());
+    }
+    let path = Path::new(location)
+        .canonicalize()
+        .with_context(|| format!("Media file not found: {}", location))?;
+    Ok(gst::glib::filename_to_uri(path, None)?.to_string())
+}
+
+/// Video source for non-platform sources; `None` for screen capture
+pub fn create_video_source(source: &CaptureSource, fps: u32) -> Result<Option<gst::Element>> {
+    match source {
+        CaptureSource::Platform => Ok(None),
+        CaptureSource::TestPattern { pattern, width, height, .. } => {
+            let src = gst::ElementFactory::make("videotestsrc")
+                .property("is-live", true)
+                .build()?;
+            set_enum(&src, "pattern", pattern)?;
+            let caps = gst::ElementFactory::make("capsfilter")
+                .property(
+                    "caps",
+                    gst::Caps::builder("video/x-raw")
+                        .field("width", *width as i32)
+                        .field("height", *height as i32)
+                        .field("framerate", gst::Fraction::new(fps as i32, 1))
+                        .build(),
+                )
+                .build()?;
+
+            tracing::info!("Using test pattern '{}' at {}x{}", pattern, width, height);
+            Ok(Some(wrap_in_bin("test-pattern", &[&src, &caps])?))
+        }
+        CaptureSource::File { uri, looped } => {
+            tracing::info!("Streaming {}{}", uri, if *looped { " (looped)" } else { "" });
+            Ok(Some(create_file_source(uri, *looped)?))
+        }
+    }
+}
+
+/// Audio source for test patterns; `None` to use the platform's
+pub fn create_audio_source(source: &CaptureSource) -> Result<Option<gst::Element>> {
+    match source {
+        CaptureSource::TestPattern { wave, .. } => {
+            let src = gst::ElementFactory::make("audiotestsrc")
+                .property("is-live", true)
+                .property("volume", 0.2f64)
+                .build()?;
+            set_enum(&src, "wave", wave)?;
+            Ok(Some(src))
+        }
+        CaptureSource::Platform | CaptureSource::File { .. } => Ok(None),
+    }
+}
+
+/// Set an enum property from a value name or nick; unlike
+/// `property_from_str`, unknown values are an error rather than a panic
+fn set_enum(element: &gst::Element, property: &str, value: &str) -> Result<()> {
+    let pspec = element
+        .find_property(property)
+        .with_context(|| format!("{} has no '{}' property", element.name(), property))?;
+    let Ok(parsed) = gst::glib::Value::deserialize(value, pspec.value_type()) else {
+        let expected = gst::glib::EnumClass::with_type(pspec.value_type())
+            .map(|class| class.values().iter().map(|v| v.nick()).collect::<Vec<_>>().join(", "))
+            .unwrap_or_default();
+        anyhow::bail!("Unknown {} '{}' (expected one of: {})", property, value, expected);
+    };
+    element.set_property_from_value(property, &parsed);
+    Ok(())
+}
+
+/// Link elements inside a bin that exposes the last one's src pad
+fn wrap_in_bin(name: &str, elements: &[&gst::Element]) -> Result<gst::Element> {
+    let bin = gst::Bin::with_name(name);
+    bin.add_many(elements.iter().copied())?;
+    gst::Element::link_many(elements.iter().copied())?;
+
+    let last = elements.last().context("empty source bin")?;
+    let pad = last.static_pad("src").context("source without src pad")?;
+    bin.add_pad(&gst::GhostPad::with_target(&pad)?)?;
+    Ok(bin.upcast())
+}
+
+/// `uridecodebin` video output, converted and paced to real time.
+///
+/// Looping drops EOS, seeks the decoder back to the start and shifts
+/// timestamps by the file's length so downstream sees one continuous stream.
+fn create_file_source(uri: &str, looped: bool) -> Result<gst::Element> {
+    let decodebin = gst::ElementFactory::make("uridecodebin")
+        .property("uri", uri)
+        .build()
+        .context("uridecodebin not available")?;
+    let convert = gst::ElementFactory::make("videoconvert").build()?;
+    // Files decode as fast as possible; play them at their own pace
+    let clocksync = gst::ElementFactory::make("clocksync").build()?;
+
+    let bin = gst::Bin::with_name("file-source");
+    bin.add_many([&decodebin, &convert, &clocksync])?;
+    convert.link(&clocksync)?;
+    let src = clocksync.static_pad("src").context("clocksync without src pad")?;
+    bin.add_pad(&gst::GhostPad::with_target(&src)?)?;
+
+    let convert_weak = convert.downgrade();
+    decodebin.connect_pad_added(move |decodebin, pad| {
+        let is_video = pad
+            .current_caps()
+            .or_else(|| Some(pad.query_caps(None)))
+            .and_then(|caps| caps.structure(0).map(|s| s.name().starts_with("video/")))
+            .unwrap_or(false);
+        let Some(convert) = convert_weak.upgrade() else { return };
+        let Some(sink) = convert.static_pad("sink") else { return };
+        if !is_video || sink.is_linked() {
+            return;
+        }
+
+        if let Err(e) = pad.link(&sink) {
+            tracing::error!("Failed to link decoded video: {:?}", e);
+            return;
+        }
+        if looped {
+            install_loop(decodebin, pad);
+        }
+    });
+
+    Ok(bin.upcast())
+}
+
+/// Restart the decoder at EOS without downstream noticing
+fn install_loop(decodebin: &gst::Element, pad: &gst::Pad) {
+    // End of the latest buffer in stream time, and the total shift so far
+    let end = AtomicU64::new(0);
+    let offset = AtomicU64::new(0);
+    let decodebin = decodebin.downgrade();
+
+    pad.add_probe(
+        gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM | gst::PadProbeType::EVENT_FLUSH,
+        move |pad, info| {
+            match &info.data {
+                Some(gst::PadProbeData::Buffer(buffer)) => {
+                    if let Some(pts) = buffer.pts() {
+                        let stop = pts + buffer.duration().unwrap_or(gst::ClockTime::ZERO);
+                        end.fetch_max(stop.nseconds(), Ordering::Relaxed);
+                    }
+                }
+                Some(gst::PadProbeData::Event(event)) => match event.type_() {
+                    // The seek's flush must not reach the encoder
+                    gst::EventType::FlushStart | gst::EventType::FlushStop => {
+                        return gst::PadProbeReturn::Drop;
+                    }
+                    gst::EventType::Eos => {
+                        let length = end.swap(0, Ordering::Relaxed);
+                        let total = offset.fetch_add(length, Ordering::Relaxed) + length;
+                        pad.set_offset(total as i64);
+
+                        // Seeking from the streaming thread would deadlock
+                        let decodebin = decodebin.clone();
+                        std::thread::spawn(move || {
+                            if let Some(decodebin) = decodebin.upgrade() {
+                                tracing::debug!("Looping media file");
+                                let _ = decodebin.seek_simple(
+                                    gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT,
+                                    gst::ClockTime::ZERO,
+                                );
+                            }
+                        });
+                        return gst::PadProbeReturn::Drop;
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+            gst::PadProbeReturn::Ok
+        },
+    );
+}
@@ -0,0 +1,129 @@
+//! Monitor enumeration via XRandR
+//!
+//! `ximagesrc` captures the whole X screen, which spans every monitor. The
+//! monitor list lets a session crop capture to a single one.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+/// A monitor on an X screen
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Monitor {
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub primary: bool,
+}
+
+/// List the monitors of an X display (`$DISPLAY` when `None`)
+pub fn list_monitors(display: Option<&str>) -> Result<Vec<Monitor>> {
+    let mut cmd = Command::new("xrandr");
+    cmd.arg("--listmonitors");
+    if let Some(display) = display {
+        cmd.env("DISPLAY", display);
+    }
+
+    let output = cmd.output().context("Failed to run xrandr — is it installed?")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "xrandr failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(parse_listmonitors(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse `xrandr --listmonitors` output:
+///
+/// ```text
+/// Monitors: 2
+///  0: +*DP-1 2560/597x1440/336+0+0  DP-1
+///  1: +HDMI-1 1920/527x1080/296+2560+0  HDMI-1
+/// ```
+fn parse_listmonitors(output: &str) -> Vec<Monitor> {
+    output.lines().skip(1).filter_map(parse_monitor_line).collect()
+}
+
+fn parse_monitor_line(line: &str) -> Option<Monitor> {
+    let (_, rest) = line.split_once(':')?;
+    let mut fields = rest.split_whitespace();
+
+    let flagged_name = fields.next()?;
+    let primary = flagged_name.contains('*');
+    let name = flagged_name.trim_start_matches(['+', '*']).to_string();
+
+    // W/mmwidthxH/mmheight+X+Y
+    let geometry = fields.next()?;
+    let (width, rest) = geometry.split_once('/')?;
+    let (_, rest) = rest.split_once('x')?;
+    let (height, rest) = rest.split_once('/')?;
+    let offsets = &rest[rest.find(['+', '-'])?..];
+    let (x, y) = parse_offsets(offsets)?;
+
+    Some(Monitor {
+        name,
+        x,
+        y,
+        width: width.parse().ok()?,
+        height: height.parse().ok()?,
+        primary,
+    })
+}
+
+/// Parse `+X+Y` (either sign allowed) into coordinates
+fn parse_offsets(s: &str) -> Option<(i32, i32)> {
+    let split = s[1..].find(['+', '-'])? + 1;
+    let x = s[..split].parse().ok()?;
+    let y = s[split..].parse().ok()?;
+    Some((x, y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_two_monitors() {
+        let output = "Monitors: 2\n \
+                      0: +*DP-1 2560/597x1440/336+0+0  DP-1\n \
+                      1: +HDMI-1 1920/527x1080/296-1920-200  HDMI-1\n";
+        assert_eq!(
+            parse_listmonitors(output),
+            vec![
+                Monitor {
+                    name: "DP-1".into(),
+                    x: 0,
+                    y: 0,
+                    width: 2560,
+                    height: 1440,
+                    primary: true,
+                },
+                Monitor {
+                    name: "HDMI-1".into(),
+                    x: -1920,
+                    y: -200,
+                    width: 1920,
+                    height: 1080,
+                    primary: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_mixed_sign_offsets() {
+        assert_eq!(parse_offsets("+2560-1080"), Some((2560, -1080)));
+        assert_eq!(parse_offsets("-10+20"), Some((-10, 20)));
+        assert_eq!(parse_offsets("+5"), None);
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        assert!(parse_listmonitors("Monitors: 1\n 0: +*DP-1 garbage  DP-1\n").is_empty());
+        assert!(parse_listmonitors("").is_empty());
+    }
+}
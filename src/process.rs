@@ -0,0 +1,87 @@
+//! Child process helpers
+//!
+//! Helper processes (X servers, window managers, streamed apps) are started in
+//! their own process group so everything they spawn is cleaned up with them.
+
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Build a command that runs in its own process group with stdio detached
+pub fn detached(program: &str) -> Command {
+    let mut cmd = Command::new(program);
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    cmd
+}
+
+/// Ask a child's process group to exit with SIGTERM, killing it if it does not comply.
+///
+/// `child` must come from [`detached`], so it leads its own group. The group is
+/// signalled even if the leader (e.g. an `sh -c` wrapper) already exited, since
+/// processes it started may still be running.
+pub fn terminate(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        let group = -(child.id() as libc::pid_t);
+        // SAFETY: plain kill(2) on the group of a child we spawned. Its id cannot
+        // be reused while the group has members, and once it has none the
+        // call fails with ESRCH.
+        let signal = |signal| unsafe { libc::kill(group, signal) == 0 };
+
+        if signal(libc::SIGTERM) {
+            let deadline = Instant::now() + TERMINATE_TIMEOUT;
+            // Reap the leader as we go: a zombie still counts as a group member
+            while Instant::now() < deadline {
+                let _ = child.try_wait();
+                if !signal(0) {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            signal(libc::SIGKILL);
+        }
+    }
+
+    if let Ok(Some(_)) = child.try_wait() {
+        return;
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn group_alive(child: &Child) -> bool {
+        // SAFETY: signal 0 only checks for existence
+        unsafe { libc::kill(-(child.id() as libc::pid_t), 0) == 0 }
+    }
+
+    #[test]
+    fn terminates_group_after_leader_exits() {
+        let mut child = detached("sh").args(["-c", "sleep 30 &"]).spawn().unwrap();
+        child.wait().unwrap();
+        assert!(group_alive(&child), "background sleep should outlive the shell");
+
+        terminate(&mut child);
+        assert!(!group_alive(&child));
+    }
+
+    #[test]
+    fn terminates_running_group() {
+        let mut child = detached("sh").args(["-c", "sleep 30 & sleep 30"]).spawn().unwrap();
+        terminate(&mut child);
+        assert!(!group_alive(&child));
+    }
+}
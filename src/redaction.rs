@@ -0,0 +1,586 @@
+//! Privacy redaction zones
+//!
+//! Screen rectangles and windows (by title or WM_CLASS) are blacked out or
+//! blurred in raw frames before they are scaled and encoded, so redacted
+//! content never leaves the server. Pointer input landing in a redacted
+//! area is dropped, and so are key presses while a redacted window has the
+//! keyboard focus or the pointer rests in a redacted area. Zones come from
+//! `REDACT_ZONES` / `REDACT_WINDOWS` and can be changed at runtime through
+//! the admin endpoints.
+//!
+//! Redacted windows are looked up periodically like followed windows; until
+//! the first lookup succeeds the whole frame is blacked out. Between lookups
+//! a window that moved or just opened is not redacted yet, for up to
+//! [`WINDOW_POLL_INTERVAL`].
+
+use crate::input::{InputEvent, InputTransform};
+use crate::screen_capture::CaptureRegion;
+use crate::window_capture::{list_windows, WindowSelector};
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_video as gst_video;
+use gstreamer_video::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// How often redacted windows are looked up again
+pub const WINDOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Block size of blurred zones, in pixels
+const BLUR_BLOCK: u32 = 24;
+
+/// How redacted areas are drawn
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactionStyle {
+    #[default]
+    Black,
+    /// Coarse blocks of the average color
+    Blur,
+}
+
+impl std::str::FromStr for RedactionStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "black" => Ok(Self::Black),
+            "blur" => Ok(Self::Blur),
+            other => anyhow::bail!("Unknown redaction style '{}' (expected black or blur)", other),
+        }
+    }
+}
+
+/// Areas to redact
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Redactions {
+    /// Screen rectangles
+    #[serde(default)]
+    pub zones: Vec<CaptureRegion>,
+    /// Window selectors (`id:`, `title:` or `class:`); every matching window is redacted
+    #[serde(default)]
+    pub windows: Vec<String>,
+    #[serde(default)]
+    pub style: RedactionStyle,
+}
+
+impl Redactions {
+    /// Read `REDACT_ZONES` (`WxH+X+Y,...`), `REDACT_WINDOWS` (selectors,
+    /// comma-separated) and `REDACT_STYLE`
+    pub fn from_env() -> Result<Self> {
+        let zones = match crate::config::env_string("REDACT_ZONES") {
+            Some(list) => list
+                .split(',')
+                .map(|zone| CaptureRegion::parse(zone).with_context(|| format!("Invalid REDACT_ZONES entry '{}'", zone)))
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+        let windows = crate::config::env_string("REDACT_WINDOWS")
+            .map(|list| list.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+        let style = match crate::config::env_string("REDACT_STYLE") {
+            Some(s) => s.parse()?,
+            None => RedactionStyle::default(),
+        };
+
+        let redactions = Self { zones, windows, style };
+        redactions.selectors().context("Invalid REDACT_WINDOWS")?;
+        if !redactions.is_empty() {
+            tracing::info!(
+                "Redacting {} zone(s) and {} window selector(s)",
+                redactions.zones.len(),
+                redactions.windows.len()
+            );
+        }
+        Ok(redactions)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty() && self.windows.is_empty()
+    }
+
+    /// Parsed window selectors; fails on the first invalid one
+    pub fn selectors(&self) -> Result<Vec<WindowSelector>> {
+        self.windows
+            .iter()
+            .map(|s| WindowSelector::parse(s).with_context(|| format!("Invalid window selector '{}'", s)))
+            .collect()
+    }
+}
+
+/// Redaction state of one capture, shared by the frame probe, input
+/// filtering and the admin endpoints
+pub struct Redactor {
+    display: Option<String>,
+    /// Screen position of the frame's top-left corner
+    transform: Arc<Mutex<InputTransform>>,
+    settings: Mutex<Redactions>,
+    /// Screen rectangles of redacted windows; `None` until first looked up
+    window_rects: Mutex<Option<Vec<CaptureRegion>>>,
+    /// Last pointer position on screen, for scroll and key events
+    pointer: Mutex<Option<(i32, i32)>>,
+    /// A redacted window (or one inside it) has the keyboard focus; assumed
+    /// until the first lookup
+    focus_redacted: AtomicBool,
+    window_watch: Mutex<Option<tokio::task::AbortHandle>>,
+}
+
+impl Redactor {
+    pub fn new(settings: Redactions, display: Option<String>, transform: Arc<Mutex<InputTransform>>) -> Arc<Self> {
+        let redactor = Arc::new(Self {
+            display,
+            transform,
+            settings: Mutex::new(Redactions::default()),
+            window_rects: Mutex::new(None),
+            pointer: Mutex::new(None),
+            focus_redacted: AtomicBool::new(false),
+            window_watch: Mutex::new(None),
+        });
+        redactor.set(settings);
+        redactor
+    }
+
+    pub fn settings(&self) -> Redactions {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// Replace the redacted areas; takes effect from the next frame
+    pub fn set(self: &Arc<Self>, settings: Redactions) {
+        let selectors = settings.selectors().unwrap_or_default();
+        *self.window_rects.lock().unwrap() = selectors.is_empty().then(Vec::new);
+        self.focus_redacted.store(!selectors.is_empty(), Ordering::Relaxed);
+        *self.settings.lock().unwrap() = settings;
+
+        let watch = (!selectors.is_empty()).then(|| tokio::spawn(watch_windows(Arc::downgrade(self), selectors)).abort_handle());
+        if let Some(old) = std::mem::replace(&mut *self.window_watch.lock().unwrap(), watch) {
+            old.abort();
+        }
+    }
+
+    /// Redacted screen rectangles; `None` if the whole screen is
+    fn areas(&self) -> Option<(RedactionStyle, Vec<CaptureRegion>)> {
+        let settings = self.settings.lock().unwrap();
+        let windows = self.window_rects.lock().unwrap();
+        let mut areas = settings.zones.clone();
+        areas.extend(windows.as_ref()?.iter().copied());
+        Some((settings.style, areas))
+    }
+
+    fn contains(&self, x: i32, y: i32) -> bool {
+        match self.areas() {
+            Some((_, areas)) => areas.iter().any(|r| {
+                x >= r.x && y >= r.y && x < r.x + r.width as i32 && y < r.y + r.height as i32
+            }),
+            None => true,
+        }
+    }
+
+    /// Whether an input event in screen coordinates may be injected.
+    /// Releasing a button or key is always allowed so nothing stays pressed.
+    pub fn allows(&self, event: &InputEvent) -> bool {
+        let pointer_redacted = || self.pointer.lock().unwrap().is_some_and(|(x, y)| self.contains(x, y));
+        match *event {
+            InputEvent::MouseDown { x, y, .. } | InputEvent::MouseMove { x, y } => {
+                let allowed = !self.contains(x, y);
+                if allowed {
+                    *self.pointer.lock().unwrap() = Some((x, y));
+                }
+                allowed
+            }
+            InputEvent::Scroll { .. } => !pointer_redacted(),
+            // Typing into a window the viewer cannot see, e.g. after Alt-Tab
+            InputEvent::KeyDown { .. } => !self.focus_redacted.load(Ordering::Relaxed) && !pointer_redacted(),
+            InputEvent::MouseUp { .. } | InputEvent::KeyUp { .. } => true,
+        }
+    }
+
+    /// Redact raw frames flowing through `pad`, which must carry unscaled
+    /// frames whose top-left corner is at the transform's origin
+    pub fn install(self: &Arc<Self>, pad: &gst::Pad) {
+        let weak = Arc::downgrade(self);
+        pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+            let Some(redactor) = weak.upgrade() else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let areas = redactor.areas();
+            if areas.as_ref().is_some_and(|(_, areas)| areas.is_empty()) {
+                return gst::PadProbeReturn::Ok;
+            }
+            // From here on something must be hidden: on any failure drop the frame
+            let Some(video_info) = pad.current_caps().and_then(|caps| gst_video::VideoInfo::from_caps(&caps).ok()) else {
+                tracing::warn!("Cannot redact frames without video caps, dropping them");
+                return gst::PadProbeReturn::Drop;
+            };
+            let Some(gst::PadProbeData::Buffer(buffer)) = &mut info.data else {
+                tracing::warn!("Redaction probe got no buffer, dropping it");
+                return gst::PadProbeReturn::Drop;
+            };
+
+            let (origin_x, origin_y) = {
+                let t = redactor.transform.lock().unwrap();
+                (t.origin_x, t.origin_y)
+            };
+            let full = CaptureRegion {
+                x: 0,
+                y: 0,
+                width: video_info.width(),
+                height: video_info.height(),
+            };
+            let (style, rects) = match areas {
+                Some((style, areas)) => (
+                    style,
+                    areas
+                        .into_iter()
+                        .map(|r| CaptureRegion { x: r.x - origin_x, y: r.y - origin_y, ..r })
+                        .collect(),
+                ),
+                None => (RedactionStyle::Black, vec![full]),
+            };
+
+            let buffer = buffer.make_mut();
+            let Ok(mut frame) = gst_video::VideoFrameRef::from_buffer_ref_writable(buffer, &video_info) else {
+                tracing::warn!("Cannot map frame for redaction, dropping it");
+                return gst::PadProbeReturn::Drop;
+            };
+            if !redact_frame(&mut frame, &rects, style) {
+                // Unsupported format: never pass unredacted content
+                tracing::warn!("Cannot redact {:?} frames, dropping them", video_info.format());
+                return gst::PadProbeReturn::Drop;
+            }
+            gst::PadProbeReturn::Ok
+        });
+    }
+}
+
+impl Drop for Redactor {
+    fn drop(&mut self) {
+        if let Some(task) = self.window_watch.get_mut().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+/// Keep the rectangles of redacted windows up to date
+async fn watch_windows(redactor: Weak<Redactor>, selectors: Vec<WindowSelector>) {
+    let mut interval = tokio::time::interval(WINDOW_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(redactor) = redactor.upgrade() else { break };
+
+        let display = redactor.display.clone();
+        let lookup = tokio::task::spawn_blocking(move || {
+            let windows = list_windows(display.as_deref())?;
+            Ok::<_, anyhow::Error>((windows, focus_chain(display.as_deref())))
+        });
+        let (windows, focus) = match lookup.await {
+            Ok(Ok(found)) => found,
+            Ok(Err(e)) => {
+                tracing::warn!("Redacted window lookup failed: {}", e);
+                continue;
+            }
+            Err(_) => continue,
+        };
+
+        let redacted: Vec<_> = windows.iter().filter(|w| selectors.iter().any(|s| s.matches(w))).collect();
+        let focus_redacted = match focus {
+            Ok(chain) => chain.iter().any(|id| redacted.iter().any(|w| w.id == *id)),
+            Err(e) => {
+                tracing::warn!("Keyboard focus lookup failed, blocking keys: {}", e);
+                true
+            }
+        };
+        let rects = redacted
+            .iter()
+            .map(|w| CaptureRegion {
+                x: w.x,
+                y: w.y,
+                width: w.width,
+                height: w.height,
+            })
+            .collect();
+        *redactor.window_rects.lock().unwrap() = Some(rects);
+        redactor.focus_redacted.store(focus_redacted, Ordering::Relaxed);
+    }
+}
+
+/// The window with the keyboard focus and its ancestors below the root
+#[cfg(target_os = "linux")]
+fn focus_chain(display: Option<&str>) -> Result<Vec<u64>> {
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::ConnectionExt as _;
+
+    let (conn, screen) = x11rb::connect(display).context("Cannot connect to X display")?;
+    let root = conn.setup().roots[screen].root;
+    let mut window = conn.get_input_focus()?.reply()?.focus;
+    let mut chain = Vec::new();
+    // 0 and 1 are None and PointerRoot, not windows
+    while window > 1 && window != root {
+        chain.push(window as u64);
+        window = conn.query_tree(window)?.reply()?.parent;
+    }
+    Ok(chain)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn focus_chain(_display: Option<&str>) -> Result<Vec<u64>> {
+    Ok(Vec::new())
+}
+
+/// One color component's layout within its plane
+struct Component {
+    plane: u32,
+    offset: usize,
+    pixel_stride: usize,
+    stride: usize,
+    w_sub: u32,
+    h_sub: u32,
+    /// Value drawn for black
+    black: u8,
+}
+
+/// Black out or blur `rects` (frame pixels) in place. Returns `false` for
+/// formats other than 8 bits per component.
+fn redact_frame(
+    frame: &mut gst_video::VideoFrameRef<&mut gst::BufferRef>,
+    rects: &[CaptureRegion],
+    style: RedactionStyle,
+) -> bool {
+    let info = frame.format_info();
+    let (width, height) = (frame.width() as i32, frame.height() as i32);
+    let mut components = Vec::new();
+    for c in 0..info.n_components() as usize {
+        if info.depth()[c] != 8 {
+            return false;
+        }
+        // Leave alpha alone
+        if info.has_alpha() && c == 3 {
+            continue;
+        }
+        let plane = info.plane()[c];
+        components.push(Component {
+            plane,
+            offset: info.poffset()[c] as usize,
+            pixel_stride: info.pixel_stride()[c] as usize,
+            stride: frame.plane_stride()[plane as usize] as usize,
+            w_sub: info.w_sub()[c],
+            h_sub: info.h_sub()[c],
+            black: if info.is_yuv() && c > 0 { 128 } else if info.is_yuv() { 16 } else { 0 },
+        });
+    }
+
+    for rect in rects {
+        let x0 = rect.x.clamp(0, width) as u32;
+        let y0 = rect.y.clamp(0, height) as u32;
+        let x1 = (rect.x + rect.width as i32).clamp(0, width) as u32;
+        let y1 = (rect.y + rect.height as i32).clamp(0, height) as u32;
+        if x0 >= x1 || y0 >= y1 {
+            continue;
+        }
+
+        for comp in &components {
+            let Ok(data) = frame.plane_data_mut(comp.plane) else { return false };
+            // Round outward so subsampled chroma covers the whole rectangle
+            let (cx0, cy0) = (x0 >> comp.w_sub, y0 >> comp.h_sub);
+            let cx1 = (x1 + (1 << comp.w_sub) - 1) >> comp.w_sub;
+            let cy1 = (y1 + (1 << comp.h_sub) - 1) >> comp.h_sub;
+            let index = |x: u32, y: u32| y as usize * comp.stride + comp.offset + x as usize * comp.pixel_stride;
+
+            match style {
+                RedactionStyle::Black => {
+                    for y in cy0..cy1 {
+                        for x in cx0..cx1 {
+                            data[index(x, y)] = comp.black;
+                        }
+                    }
+                }
+                RedactionStyle::Blur => {
+                    let block_w = (BLUR_BLOCK >> comp.w_sub).max(1);
+                    let block_h = (BLUR_BLOCK >> comp.h_sub).max(1);
+                    for by in (cy0..cy1).step_by(block_h as usize) {
+                        for bx in (cx0..cx1).step_by(block_w as usize) {
+                            let (ex, ey) = ((bx + block_w).min(cx1), (by + block_h).min(cy1));
+                            let mut sum = 0u32;
+                            for y in by..ey {
+                                for x in bx..ex {
+                                    sum += data[index(x, y)] as u32;
+                                }
+                            }
+                            let average = (sum / ((ex - bx) * (ey - by))) as u8;
+                            for y in by..ey {
+                                for x in bx..ex {
+                                    data[index(x, y)] = average;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Modifiers;
+
+    const SIZE: u32 = 8;
+
+    /// An 8×8 I420 frame with every plane filled by `fill(plane, x, y)`
+    fn i420(fill: impl Fn(usize, u32, u32) -> u8) -> (gst::Buffer, gst_video::VideoInfo) {
+        gst::init().unwrap();
+        let info = gst_video::VideoInfo::builder(gst_video::VideoFormat::I420, SIZE, SIZE).build().unwrap();
+        let mut buffer = gst::Buffer::with_size(info.size()).unwrap();
+        {
+            let mut frame = gst_video::VideoFrameRef::from_buffer_ref_writable(buffer.get_mut().unwrap(), &info).unwrap();
+            for plane in 0..3 {
+                let (w, h) = if plane == 0 { (SIZE, SIZE) } else { (SIZE / 2, SIZE / 2) };
+                let stride = frame.plane_stride()[plane] as usize;
+                let data = frame.plane_data_mut(plane as u32).unwrap();
+                for y in 0..h {
+                    for x in 0..w {
+                        data[y as usize * stride + x as usize] = fill(plane, x, y);
+                    }
+                }
+            }
+        }
+        (buffer, info)
+    }
+
+    fn redact(buffer: &mut gst::Buffer, info: &gst_video::VideoInfo, rects: &[CaptureRegion], style: RedactionStyle) {
+        let mut frame = gst_video::VideoFrameRef::from_buffer_ref_writable(buffer.get_mut().unwrap(), info).unwrap();
+        assert!(redact_frame(&mut frame, rects, style));
+    }
+
+    fn pixel(buffer: &gst::Buffer, info: &gst_video::VideoInfo, plane: usize, x: u32, y: u32) -> u8 {
+        let frame = gst_video::VideoFrameRef::from_buffer_ref_readable(buffer.as_ref(), info).unwrap();
+        let stride = frame.plane_stride()[plane] as usize;
+        frame.plane_data(plane as u32).unwrap()[y as usize * stride + x as usize]
+    }
+
+    fn rect(x: i32, y: i32, width: u32, height: u32) -> CaptureRegion {
+        CaptureRegion { x, y, width, height }
+    }
+
+    #[test]
+    fn black_covers_odd_rect_and_its_chroma() {
+        let (mut buffer, info) = i420(|_, _, _| 200);
+        redact(&mut buffer, &info, &[rect(1, 1, 3, 3)], RedactionStyle::Black);
+
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let inside = (1..4).contains(&x) && (1..4).contains(&y);
+                assert_eq!(pixel(&buffer, &info, 0, x, y), if inside { 16 } else { 200 }, "luma {},{}", x, y);
+            }
+        }
+        // Luma 1..4 touches chroma samples 0 and 1 in both directions
+        for plane in 1..3 {
+            for y in 0..SIZE / 2 {
+                for x in 0..SIZE / 2 {
+                    let inside = x < 2 && y < 2;
+                    assert_eq!(pixel(&buffer, &info, plane, x, y), if inside { 128 } else { 200 }, "chroma {},{}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blur_averages_odd_rect_and_its_chroma() {
+        let (mut buffer, info) = i420(|plane, x, y| (plane as u32 * 50 + x * 10 + y) as u8);
+        let before = buffer.copy_deep().unwrap();
+        redact(&mut buffer, &info, &[rect(1, 1, 5, 5)], RedactionStyle::Blur);
+
+        // The rectangle is smaller than a blur block, so it becomes one flat average
+        let luma_average = {
+            let sum: u32 = (1..6).flat_map(|y| (1..6).map(move |x| x * 10 + y)).sum();
+            (sum / 25) as u8
+        };
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let inside = (1..6).contains(&x) && (1..6).contains(&y);
+                let expected = if inside { luma_average } else { pixel(&before, &info, 0, x, y) };
+                assert_eq!(pixel(&buffer, &info, 0, x, y), expected, "luma {},{}", x, y);
+            }
+        }
+        // Luma 1..6 maps to chroma 0..3
+        for plane in 1..3 {
+            let sum: u32 = (0..3).flat_map(|y| (0..3).map(move |x| plane as u32 * 50 + x * 10 + y)).sum();
+            for y in 0..SIZE / 2 {
+                for x in 0..SIZE / 2 {
+                    let inside = x < 3 && y < 3;
+                    let expected = if inside { (sum / 9) as u8 } else { pixel(&before, &info, plane, x, y) };
+                    assert_eq!(pixel(&buffer, &info, plane, x, y), expected, "chroma {},{}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rects_are_clamped_to_the_frame() {
+        let (mut buffer, info) = i420(|_, _, _| 200);
+        redact(
+            &mut buffer,
+            &info,
+            &[rect(-3, -3, 6, 6), rect(6, 6, 10, 10), rect(20, 20, 4, 4), rect(-10, 0, 4, 4)],
+            RedactionStyle::Black,
+        );
+
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let inside = (x < 3 && y < 3) || (x >= 6 && y >= 6);
+                assert_eq!(pixel(&buffer, &info, 0, x, y), if inside { 16 } else { 200 }, "luma {},{}", x, y);
+            }
+        }
+    }
+
+    fn key_down() -> InputEvent {
+        InputEvent::KeyDown { key: "a".into(), code: "KeyA".into(), modifiers: Modifiers::default() }
+    }
+
+    fn key_up() -> InputEvent {
+        InputEvent::KeyUp { key: "a".into(), code: "KeyA".into(), modifiers: Modifiers::default() }
+    }
+
+    fn redactor(settings: Redactions) -> Arc<Redactor> {
+        Redactor::new(settings, None, Arc::new(Mutex::new(InputTransform::default())))
+    }
+
+    #[tokio::test]
+    async fn everything_redacted_before_first_window_lookup() {
+        let redactor = redactor(Redactions {
+            windows: vec!["class:emr-client".to_string()],
+            ..Default::default()
+        });
+
+        assert!(redactor.areas().is_none());
+        assert!(!redactor.allows(&InputEvent::MouseMove { x: 5, y: 5 }));
+        assert!(!redactor.allows(&InputEvent::MouseDown { button: 0, x: 5, y: 5 }));
+        assert!(!redactor.allows(&key_down()));
+        assert!(redactor.allows(&InputEvent::MouseUp { button: 0, x: 5, y: 5 }));
+        assert!(redactor.allows(&key_up()));
+    }
+
+    #[test]
+    fn key_presses_dropped_while_focus_redacted() {
+        let redactor = redactor(Redactions {
+            zones: vec![rect(100, 100, 50, 50)],
+            ..Default::default()
+        });
+
+        assert!(redactor.allows(&InputEvent::MouseMove { x: 10, y: 10 }));
+        assert!(redactor.allows(&key_down()));
+
+        redactor.focus_redacted.store(true, Ordering::Relaxed);
+        assert!(!redactor.allows(&key_down()));
+        assert!(redactor.allows(&key_up()));
+        assert!(redactor.allows(&InputEvent::MouseUp { button: 0, x: 120, y: 120 }));
+
+        // Pointer inside a zone blocks clicks there, but releases still pass
+        redactor.focus_redacted.store(false, Ordering::Relaxed);
+        assert!(!redactor.allows(&InputEvent::MouseDown { button: 0, x: 120, y: 120 }));
+        assert!(redactor.allows(&InputEvent::MouseUp { button: 0, x: 120, y: 120 }));
+    }
+}
@@ -3,13 +3,39 @@
 //! Captures the screen/display and system audio, streams via WebRTC - like a VDI.
 //! Also receives microphone audio from the browser and plays it locally.
 
+use crate::bus_monitor::{self, PipelineFailure};
+use crate::congestion::{self, CongestionConfig};
+use crate::cursor;
+use crate::display_mode::resize_screen;
+use crate::encoder::{
+    await_startup, Codec, EncoderChoice, EncoderControl, EncoderPreset, EncoderSettings,
+    EncoderUpdate, QualityProfile,
+};
+use crate::frame_skip;
+use crate::ice::{IceConfig, IceServer, IceTransportPolicy};
+use crate::input::{InputEvent, InputTransform};
+use crate::keyframes::KeyframeLimiter;
+use crate::latency::{self, LatencyProbe, LatencyReport};
+use crate::media_source::{self, CaptureSource};
+use crate::monitors::{list_monitors, Monitor};
+use crate::redaction::{Redactions, Redactor};
+use crate::simulcast::{self, SimulcastFeed, SimulcastHub};
+use crate::video_scaler::VideoScaler;
+use crate::watermark::{Viewer, WatermarkConfig};
+use crate::window_capture::{list_windows, WindowInfo, WindowSelector};
 use anyhow::{Context, Result};
 use gstreamer as gst;
 use gstreamer::prelude::*;
 use gstreamer_webrtc as gst_webrtc;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::AtomicU32;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 use tokio::sync::mpsc;
 
+/// How often a followed window is looked up again
+const WINDOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 /// WebRTC signaling messages
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -17,131 +43,350 @@ pub enum SignalingMessage {
     Offer { sdp: String },
     Answer { sdp: String },
     Ice { candidate: String, sdp_mid: Option<String>, sdp_m_line_index: Option<u32> },
+    /// Server is ending the session (e.g. the streamed application exited)
+    SessionEnded { reason: String },
+    /// Client asks for the monitors of the captured display
+    ListDisplays,
+    /// Monitors of the captured display and the one being streamed
+    Displays { displays: Vec<Monitor>, selected: Option<String> },
+    /// Client picks a monitor to stream (`None` for the whole screen)
+    SelectDisplay { name: Option<String> },
+    /// Client changes the captured screen rectangle (`None` for the whole screen)
+    SetRegion { region: Option<CaptureRegion> },
+    /// Captured screen rectangle after a change
+    Region { region: Option<CaptureRegion> },
+    /// Client asks for the windows of the captured display
+    ListWindows,
+    /// Titled windows of the captured display
+    Windows { windows: Vec<WindowInfo> },
+    /// Client picks a window to stream by `id:`, `title:` or `class:` (`None` for the screen)
+    SelectWindow { window: Option<String> },
+    /// Window currently streamed, sent whenever it is found, moves or resizes
+    Window { window: Option<WindowInfo> },
+    /// Video codecs the client can decode, sent before anything else
+    Capabilities {
+        codecs: Vec<String>,
+        /// Those it can also decode with 4:4:4 chroma
+        #[serde(default)]
+        codecs_444: Vec<String>,
+        /// Quality profile wanted for the session (server default if unset)
+        #[serde(default)]
+        profile: Option<QualityProfile>,
+    },
+    /// Client viewport size in device pixels, used to pick the output resolution
+    Viewport { width: u32, height: u32 },
+    /// Request a keyframe, e.g. after the client's video stalled
+    Refresh,
+    /// Request the current encoder settings
+    GetEncoder,
+    /// Change encoder settings; omitted fields are left unchanged
+    SetEncoder {
+        #[serde(flatten)]
+        update: EncoderUpdate,
+    },
+    /// Encoder settings now in effect, sent after `get_encoder` / `set_encoder`
+    Encoder { settings: EncoderSettings },
+    /// The pipeline failed; it is rebuilt and renegotiated if `restarting`
+    StreamError { reason: String, restarting: bool },
+    /// STUN/TURN servers for the client's peer connection, sent before the offer
+    IceServers {
+        ice_servers: Vec<IceServer>,
+        ice_transport_policy: IceTransportPolicy,
+    },
+    /// Server clock, for the client to echo when measuring latency
+    LatencyPing { server_time: f64 },
+    /// Echo of a ping with the client's clock (ms since the Unix epoch)
+    LatencyPong { server_time: f64, client_time: f64 },
+    /// Client showed the frame with this RTP timestamp; client clock, ms since the Unix epoch
+    FrameDisplayed {
+        rtp_timestamp: u32,
+        received_at: Option<f64>,
+        displayed_at: f64,
+    },
+    /// Average latency per stage over the last few seconds
+    Latency { report: LatencyReport },
+}
+
+/// Per-session streaming settings
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    pub fps: u32,
+    /// X display to capture instead of `$DISPLAY` (e.g. a virtual display)
+    pub display: Option<String>,
+    /// Monitor to stream initially (whole screen when unset)
+    pub monitor: Option<String>,
+    /// Screen rectangle to capture initially, if not a whole monitor
+    pub region: Option<CaptureRegion>,
+    /// Window to capture and follow instead of the screen
+    pub window: Option<WindowSelector>,
+    /// Upper bound for the scaled output resolution
+    pub max_resolution: Option<(u32, u32)>,
+    /// Resize the X screen to the client viewport instead of scaling
+    pub resize_display: bool,
+    /// Encoder bitrate and adaptation settings
+    pub congestion: CongestionConfig,
+    /// Maximum frames between keyframes
+    pub keyframe_interval: u32,
+    pub preset: EncoderPreset,
+    /// Minimum time between keyframes forced by viewer requests
+    pub keyframe_min_interval: Duration,
+    /// Codecs in server preference order
+    pub codecs: Vec<Codec>,
+    /// Codecs the client can decode (`None` if it did not say)
+    pub client_codecs: Option<Vec<String>>,
+    /// Codecs the client can decode with 4:4:4 chroma
+    pub client_codecs_444: Vec<String>,
+    /// Standard or sharper text encoding
+    pub profile: QualityProfile,
+    /// Forced / excluded encoders
+    pub encoders: EncoderChoice,
+    /// Keep-alive frame rate for static content; `None` encodes every frame
+    pub idle_fps: Option<f64>,
+    /// Screen, test pattern or media file
+    pub source: CaptureSource,
+    /// STUN/TURN servers and ICE policy
+    pub ice: IceConfig,
+    /// Rebuild the pipeline when it fails while streaming
+    pub auto_restart: bool,
+    /// Make the pipeline fail after this many frames, for testing
+    pub simulate_error_after: Option<u32>,
+    /// Measure glass-to-glass latency with the client's help
+    pub latency_probe: bool,
+    /// Leave the pointer out of the video and send it to the client to draw
+    pub client_cursor: bool,
+    /// Screen areas and windows hidden from viewers
+    pub redactions: Redactions,
+    /// Burn viewer identity into the video
+    pub watermark: Option<WatermarkConfig>,
+    /// Who this session streams to
+    pub viewer: Viewer,
+}
+
+impl StreamConfig {
+    /// Whether screen capture should draw the pointer into the video
+    pub fn show_pointer(&self) -> bool {
+        !(self.client_cursor && cursor::supported())
+    }
+}
+
+/// A rectangle of the screen, in screen pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureRegion {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CaptureRegion {
+    /// Parse X11 geometry syntax, `WIDTHxHEIGHT+X+Y`
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let split = s.find(['+', '-'])?;
+        let (width, height) = crate::config::parse_resolution(&s[..split])?;
+        let offsets = &s[split..];
+        let y_split = offsets[1..].find(['+', '-'])? + 1;
+        Some(Self {
+            x: offsets[..y_split].parse().ok()?,
+            y: offsets[y_split..].parse().ok()?,
+            width,
+            height,
+        })
+    }
+}
+
+/// Client requests that changed a session's capture or encoding, replayed
+/// on a rebuilt pipeline
+#[derive(Debug, Default)]
+struct ClientChanges {
+    /// Last monitor, region or window selection
+    placement: Option<SignalingMessage>,
+    viewport: Option<SignalingMessage>,
+    /// Encoder settings changed so far, latest value of each
+    encoder: EncoderUpdate,
+}
+
+/// Capture, scaling and encoding owned by one session
+struct LocalVideo {
+    capture_src: gst::Element,
+    /// Crop element for sources that cannot crop themselves
+    crop: Option<gst::Element>,
+    /// Output resolution control
+    scaler: Arc<VideoScaler>,
+    encoder: Arc<EncoderControl>,
+    /// Forwards PLI/FIR and `refresh` keyframe requests to the encoder
+    keyframes: Option<Arc<KeyframeLimiter>>,
+    /// Forensic watermark overlay
+    watermark: Option<gst::Element>,
+}
+
+/// Where a session's encoded video comes from
+enum VideoSource {
+    /// The session's own capture pipeline
+    Local(LocalVideo),
+    /// A layer of the encodes shared by all viewers
+    Simulcast(Arc<SimulcastFeed>),
+}
+
+impl VideoSource {
+    fn codec(&self) -> Codec {
+        match self {
+            Self::Local(local) => local.encoder.codec(),
+            Self::Simulcast(feed) => feed.codec(),
+        }
+    }
 }
 
 /// Screen capture streamer using GStreamer WebRTC
 pub struct ScreenStreamer {
     pipeline: gst::Pipeline,
     webrtcbin: gst::Element,
+    video: VideoSource,
     outgoing_tx: mpsc::UnboundedSender<SignalingMessage>,
+    /// X display being captured (`$DISPLAY` when `None`)
+    display: Option<String>,
+    /// Captured screen rectangle (`None` for the whole screen)
+    region: Arc<Mutex<Option<CaptureRegion>>>,
+    /// Monitor currently streamed, if capture is cropped to one
+    selected_monitor: Mutex<Option<String>>,
+    /// Window currently streamed, if following one
+    captured_window: Mutex<Option<WindowInfo>>,
+    /// Task that keeps looking up the followed window
+    window_follow: Mutex<Option<tokio::task::AbortHandle>>,
+    /// Maps video coordinates from the client to screen coordinates
+    input_transform: Arc<Mutex<InputTransform>>,
+    /// Whether client viewports resize the X screen
+    resize_display: bool,
+    /// Serializes XRandR mode changes
+    resize_lock: Arc<Mutex<()>>,
+    congestion: CongestionConfig,
+    /// TWCC bandwidth estimate in bit/s, when available
+    bandwidth_estimate: Option<Arc<AtomicU32>>,
+    ice: IceConfig,
+    /// Adaptive bitrate control loop
+    congestion_task: Mutex<Option<tokio::task::AbortHandle>>,
+    latency: Option<Arc<LatencyProbe>>,
+    /// Clock pings and latency reports
+    latency_task: Mutex<Option<tokio::task::AbortHandle>>,
+    /// Whether the client draws the pointer
+    client_cursor: bool,
+    /// Sends pointer position and shape over the cursor data channel
+    cursor_task: Mutex<Option<cursor::CursorTracker>>,
+    /// Redaction of the captured frames, shared with the simulcast hub if forwarding it
+    redactor: Arc<Redactor>,
+    /// Keeps the watermark's time current
+    watermark_task: Option<tokio::task::AbortHandle>,
+    client_changes: Mutex<ClientChanges>,
 }
 
 impl ScreenStreamer {
-    /// Create a new screen capture streamer
+    /// Create a new screen capture streamer, forwarding a layer of `simulcast`
+    /// instead of capturing and encoding itself if given
     pub fn new(
-        fps: u32,
+        config: &StreamConfig,
+        simulcast: Option<Arc<SimulcastHub>>,
         outgoing_tx: mpsc::UnboundedSender<SignalingMessage>,
     ) -> Result<Self> {
         let pipeline = gst::Pipeline::new();
+        let region = Arc::new(Mutex::new(None));
+
+        let (video, mut video_chain, redactor, input_transform) = match simulcast {
+            Some(hub) => {
+                // Follows the scaling of the layer being forwarded
+                let feed = hub.subscribe(config.congestion.start_kbps);
+                let chain = vec![feed.appsrc().clone()];
+                let input_transform = feed.input_transform();
+                (VideoSource::Simulcast(feed), chain, hub.redactor(), input_transform)
+            }
+            None => {
+                let input_transform = Arc::new(Mutex::new(InputTransform::default()));
+                let redactor = Redactor::new(config.redactions.clone(), config.display.clone(), input_transform.clone());
+                let (video, chain) = Self::create_local_video(config, &region, &input_transform, &redactor)?;
+                (video, chain, redactor, input_transform)
+            }
+        };
+        let codec = video.codec();
+        tracing::info!("Streaming {}", codec.encoding_name());
+        let watermark_task = match (&video, &config.watermark) {
+            (VideoSource::Local(LocalVideo { watermark: Some(overlay), .. }), Some(watermark)) => {
+                watermark.spawn_clock(overlay, config.viewer.clone())
+            }
+            _ => None,
+        };
+        video_chain.extend(bus_monitor::create_fault_injector(config.simulate_error_after));
 
-        // Screen capture source - platform specific
-        #[cfg(target_os = "macos")]
-        let capture_src = Self::create_macos_capture(fps)?;
-
-        #[cfg(target_os = "linux")]
-        let capture_src = Self::create_linux_capture(fps)?;
-
-        #[cfg(target_os = "windows")]
-        let capture_src = Self::create_windows_capture(fps)?;
-
-        // Queue for buffering
-        let queue = gst::ElementFactory::make("queue")
-            .property("max-size-buffers", 3u32)
-            .property("max-size-time", 0u64)
-            .property("max-size-bytes", 0u32)
-            .build()?;
-
-        // Video conversion
-        let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
-
-        // Video scaling (optional - for bandwidth control)
-        let videoscale = gst::ElementFactory::make("videoscale").build()?;
-        let capsfilter = gst::ElementFactory::make("capsfilter")
-            .property(
-                "caps",
-                gst::Caps::builder("video/x-raw")
-                    .field("framerate", gst::Fraction::new(fps as i32, 1))
-                    .build(),
-            )
-            .build()?;
-
-        // Another queue before encoder
-        let queue2 = gst::ElementFactory::make("queue")
-            .property("max-size-buffers", 3u32)
-            .build()?;
-
-        // H.264 encoder - try hardware first, fall back to software
-        let encoder = Self::create_encoder()?;
-
-        // H.264 parser
-        let h264parse = gst::ElementFactory::make("h264parse")
-            .property("config-interval", -1i32)
-            .build()?;
+        // Bitstream parser, if the codec needs one
+        video_chain.extend(codec.create_parser()?);
 
         // RTP payloader
-        let rtppay = gst::ElementFactory::make("rtph264pay")
-            .property("config-interval", -1i32)
-            .property_from_str("aggregate-mode", "zero-latency")
-            .build()?;
-
-        // RTP caps filter
+        video_chain.push(codec.create_payloader()?);
+
+        // RTP caps filter, negotiating keyframe requests and TWCC feedback
+        let use_twcc = config.congestion.adaptive && congestion::twcc_available();
+        let mut rtp_caps = gst::Caps::builder("application/x-rtp")
+            .field("media", "video")
+            .field("encoding-name", codec.encoding_name())
+            .field("payload", 96i32)
+            .field("rtcp-fb-nack-pli", true)
+            .field("rtcp-fb-ccm-fir", true);
+        // 4:4:4 VP9 is profile 1, which has to be signalled
+        let chroma_444 = match &video {
+            VideoSource::Local(local) => local.encoder.settings().chroma_444,
+            VideoSource::Simulcast(_) => false,
+        };
+        if codec == Codec::Vp9 && chroma_444 {
+            rtp_caps = rtp_caps.field("profile-id", "1");
+        }
+        if use_twcc {
+            rtp_caps = rtp_caps
+                .field("extmap-1", congestion::TWCC_URI)
+                .field("rtcp-fb-transport-cc", true);
+        }
         let rtpcaps = gst::ElementFactory::make("capsfilter")
-            .property(
-                "caps",
-                gst::Caps::builder("application/x-rtp")
-                    .field("media", "video")
-                    .field("encoding-name", "H264")
-                    .field("payload", 96i32)
-                    .build(),
-            )
+            .property("caps", rtp_caps.build())
             .build()?;
+        video_chain.push(rtpcaps);
+        video_chain.extend(congestion::create_network_simulator(&config.congestion));
 
         // WebRTC bin
         let webrtcbin = gst::ElementFactory::make("webrtcbin")
             .name("webrtcbin")
             .property_from_str("bundle-policy", "max-bundle")
-            .property("stun-server", "stun://stun.l.google.com:19302")
             .build()?;
 
+        // Both sides use the same servers, with credentials issued for this session
+        let ice_servers = config.ice.servers();
+        config.ice.configure(&webrtcbin, &ice_servers);
+        let _ = outgoing_tx.send(SignalingMessage::IceServers {
+            ice_servers,
+            ice_transport_policy: config.ice.policy,
+        });
+
         // Add all elements to pipeline
-        pipeline.add_many([
-            &capture_src,
-            &queue,
-            &videoconvert,
-            &videoscale,
-            &capsfilter,
-            &queue2,
-            &encoder,
-            &h264parse,
-            &rtppay,
-            &rtpcaps,
-            &webrtcbin,
-        ])?;
+        pipeline.add_many(&video_chain)?;
+        pipeline.add(&webrtcbin)?;
 
         // Link elements
-        gst::Element::link_many([
-            &capture_src,
-            &queue,
-            &videoconvert,
-            &videoscale,
-            &capsfilter,
-            &queue2,
-            &encoder,
-            &h264parse,
-            &rtppay,
-            &rtpcaps,
-        ])?;
+        gst::Element::link_many(&video_chain)?;
 
         // Link video to webrtcbin
-        let rtpcaps_src = rtpcaps.static_pad("src")
-            .context("capsfilter missing src pad")?;
+        let video_src = video_chain.last().unwrap().static_pad("src")
+            .context("video chain missing src pad")?;
         let webrtc_video_sink = webrtcbin.request_pad_simple("sink_%u")
             .context("webrtcbin failed to create sink pad — check that webrtc, srtp, dtls, and nice plugins are loaded")?;
-        rtpcaps_src.link(&webrtc_video_sink)?;
+        video_src.link(&webrtc_video_sink)?;
+
+        let latency = config.latency_probe.then(|| {
+            let encoder = match &video {
+                VideoSource::Local(local) => Some(local.encoder.element()),
+                VideoSource::Simulcast(_) => None,
+            };
+            LatencyProbe::install(encoder, &video_src)
+        });
 
         // Add audio pipeline if enabled
         if std::env::var("ENABLE_AUDIO").unwrap_or_default() == "1" {
-            if let Err(e) = Self::add_audio_pipeline(&pipeline, &webrtcbin) {
+            if let Err(e) = Self::add_audio_pipeline(&pipeline, &webrtcbin, &config.source) {
                 tracing::warn!("Audio capture not available: {}", e);
             }
         }
@@ -149,6 +394,9 @@ impl ScreenStreamer {
         // Set up handler for incoming audio from browser (mic)
         Self::setup_incoming_audio(&pipeline, &webrtcbin);
 
+        let bandwidth_estimate = use_twcc
+            .then(|| congestion::install_bandwidth_estimator(&webrtcbin, &config.congestion));
+
         // Set up WebRTC callbacks
         let tx = outgoing_tx.clone();
         webrtcbin.connect("on-negotiation-needed", false, move |_| {
@@ -157,9 +405,14 @@ impl ScreenStreamer {
         });
 
         let tx = outgoing_tx.clone();
+        let ice = config.ice.clone();
         webrtcbin.connect("on-ice-candidate", false, move |values| {
             let sdp_m_line_index = values[1].get::<u32>().unwrap();
             let candidate = values[2].get::<String>().unwrap();
+            let Some(candidate) = ice.local_candidate(&candidate) else {
+                tracing::debug!("Withholding ICE candidate {}", candidate);
+                return None;
+            };
 
             let _ = tx.send(SignalingMessage::Ice {
                 candidate,
@@ -186,15 +439,221 @@ impl ScreenStreamer {
 
         tracing::info!("Screen capture pipeline created");
 
-        Ok(Self {
+        let streamer = Self {
             pipeline,
             webrtcbin,
+            video,
             outgoing_tx,
-        })
+            display: config.display.clone(),
+            region,
+            selected_monitor: Mutex::new(None),
+            captured_window: Mutex::new(None),
+            window_follow: Mutex::new(None),
+            input_transform,
+            resize_display: config.resize_display,
+            resize_lock: Arc::new(Mutex::new(())),
+            congestion: config.congestion.clone(),
+            bandwidth_estimate,
+            ice: config.ice.clone(),
+            congestion_task: Mutex::new(None),
+            latency,
+            latency_task: Mutex::new(None),
+            client_cursor: !config.show_pointer(),
+            cursor_task: Mutex::new(None),
+            redactor,
+            watermark_task,
+            client_changes: Mutex::new(ClientChanges::default()),
+        };
+
+        // The shared simulcast capture always covers the whole screen
+        let local = matches!(streamer.video, VideoSource::Local(_));
+        if let Some(name) = config.monitor.as_ref().filter(|_| local) {
+            if let Err(e) = streamer.select_monitor(Some(name)) {
+                tracing::warn!("Cannot stream monitor '{}': {}", name, e);
+            }
+        } else if local && config.region.is_some() {
+            if let Err(e) = streamer.set_capture_region(config.region) {
+                tracing::warn!("Cannot capture region: {}", e);
+            }
+        }
+
+        Ok(streamer)
+    }
+
+    /// Capture, scaling and encoding chain for a session of its own, up to
+    /// the encoder
+    fn create_local_video(
+        config: &StreamConfig,
+        region: &Arc<Mutex<Option<CaptureRegion>>>,
+        input_transform: &Arc<Mutex<InputTransform>>,
+        redactor: &Arc<Redactor>,
+    ) -> Result<(VideoSource, Vec<gst::Element>)> {
+        let fps = config.fps;
+
+        // Capture source - test pattern or file if configured, otherwise the screen
+        let capture_src = match media_source::create_video_source(&config.source, fps)? {
+            Some(src) => src,
+            None => Self::create_screen_capture(fps, config.display.as_deref(), config.show_pointer())?,
+        };
+
+        // Only damaged screen areas need copying when static frames are skipped
+        if config.idle_fps.is_some() && is_ximagesrc(&capture_src) {
+            capture_src.set_property("use-damage", true);
+        }
+
+        // Sources without built-in cropping get a crop element for capture regions
+        let crop = if is_ximagesrc(&capture_src) {
+            None
+        } else {
+            Some(Self::create_crop(region)?)
+        };
+
+        // Queue for buffering
+        let queue = gst::ElementFactory::make("queue")
+            .property("max-size-buffers", 3u32)
+            .property("max-size-time", 0u64)
+            .property("max-size-bytes", 0u32)
+            .build()?;
+
+        // Video conversion
+        let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+
+        // Video scaling - output size follows the client viewport
+        let videoscale = gst::ElementFactory::make("videoscale").build()?;
+        let capsfilter = gst::ElementFactory::make("capsfilter").build()?;
+        let scaler = VideoScaler::new(
+            capsfilter.clone(),
+            fps,
+            config.max_resolution,
+            input_transform.clone(),
+        );
+        scaler.watch_source(&videoscale);
+
+        // Redact before scaling, while frame pixels are still screen pixels
+        if let Some(pad) = videoscale.static_pad("sink") {
+            redactor.install(&pad);
+        }
+
+        // Drops frames when congestion control lowers the frame rate
+        let videorate = gst::ElementFactory::make("videorate")
+            .property("drop-only", true)
+            .build()?;
+
+        // Watermark at output resolution, so it stays legible when scaled down
+        let watermark = config
+            .watermark
+            .as_ref()
+            .map(|watermark| watermark.create_overlay(&config.viewer))
+            .transpose()?;
+
+        // Another queue before encoder
+        let queue2 = gst::ElementFactory::make("queue")
+            .property("max-size-buffers", 3u32)
+            .build()?;
+
+        // Skip unchanged frames before they reach the encoder
+        if let (Some(idle_fps), Some(pad)) = (config.idle_fps, queue2.static_pad("sink")) {
+            frame_skip::install(&pad, idle_fps);
+            tracing::info!("Skipping static frames, keep-alive at {} fps", idle_fps);
+        }
+
+        // Encoder for the preferred codec the client supports - hardware
+        // first, falling back to software and then to the next codec
+        let codecs = Codec::candidates(
+            &config.codecs,
+            config.client_codecs.as_deref(),
+            &config.encoders,
+        );
+        let mut settings = EncoderSettings {
+            bitrate: config.congestion.start_kbps,
+            fps,
+            keyframe_interval: config.keyframe_interval,
+            preset: config.preset,
+            profile: config.profile,
+            chroma_444: false,
+        };
+
+        // The text profile prefers a 4:4:4 encode the client can decode
+        let full_chroma = match config.profile {
+            QualityProfile::Text => {
+                let codecs_444 = Codec::candidates_444(&config.codecs, &config.client_codecs_444, &config.encoders);
+                let settings_444 = EncoderSettings { chroma_444: true, ..settings };
+                match EncoderControl::create(&codecs_444, &config.encoders, settings_444) {
+                    Ok(encoder) => Some(encoder),
+                    Err(e) => {
+                        tracing::info!("No 4:4:4 encode for the text profile, using 4:2:0: {}", e);
+                        None
+                    }
+                }
+            }
+            QualityProfile::Standard => None,
+        };
+        let encoder = match full_chroma {
+            Some(encoder) => {
+                settings.chroma_444 = true;
+                encoder
+            }
+            None => EncoderControl::create(&codecs, &config.encoders, settings)?,
+        };
+        let encoder = Arc::new(encoder);
+        let keyframes = KeyframeLimiter::install(encoder.element(), config.keyframe_min_interval);
+
+        let mut chain = vec![capture_src.clone()];
+        chain.extend(crop.clone());
+        chain.extend([
+            queue,
+            videoconvert,
+            videoscale,
+            videorate,
+            capsfilter,
+        ]);
+        chain.extend(watermark.clone());
+        chain.push(queue2);
+        if settings.chroma_444 {
+            chain.push(
+                gst::ElementFactory::make("capsfilter")
+                    .property("caps", gst::Caps::builder("video/x-raw").field("format", "Y444").build())
+                    .build()?,
+            );
+        }
+        chain.push(encoder.element().clone());
+
+        let local = LocalVideo {
+            capture_src,
+            crop,
+            scaler,
+            encoder,
+            keyframes,
+            watermark,
+        };
+        Ok((VideoSource::Local(local), chain))
+    }
+
+    /// Session's own capture, scaling and encoding
+    fn local(&self) -> Result<&LocalVideo> {
+        match &self.video {
+            VideoSource::Local(local) => Ok(local),
+            VideoSource::Simulcast(_) => {
+                anyhow::bail!("Not available while streaming the shared simulcast encodes")
+            }
+        }
+    }
+
+    /// Screen capture source - platform specific
+    #[allow(unused_variables)]
+    pub fn create_screen_capture(fps: u32, display: Option<&str>, show_pointer: bool) -> Result<gst::Element> {
+        #[cfg(target_os = "macos")]
+        return Self::create_macos_capture(fps, show_pointer);
+
+        #[cfg(target_os = "linux")]
+        return Self::create_linux_capture(fps, display, show_pointer);
+
+        #[cfg(target_os = "windows")]
+        return Self::create_windows_capture(fps, show_pointer);
     }
 
     #[cfg(target_os = "macos")]
-    fn create_macos_capture(_fps: u32) -> Result<gst::Element> {
+    fn create_macos_capture(_fps: u32, show_pointer: bool) -> Result<gst::Element> {
         // avfvideosrc captures screen on macOS
         // capture-screen=true captures the display instead of camera
         // do-timestamp=true is critical for live sources
@@ -206,7 +665,7 @@ impl ScreenStreamer {
 
         let src = gst::ElementFactory::make("avfvideosrc")
             .property("capture-screen", true)
-            .property("capture-screen-cursor", true)
+            .property("capture-screen-cursor", show_pointer)
             .property("do-timestamp", true)
             .property("device-index", display_index)
             .build()
@@ -217,12 +676,26 @@ impl ScreenStreamer {
     }
 
     #[cfg(target_os = "linux")]
-    fn create_linux_capture(_fps: u32) -> Result<gst::Element> {
+    fn create_linux_capture(_fps: u32, display: Option<&str>, show_pointer: bool) -> Result<gst::Element> {
+        // An explicit display (e.g. our own Xvfb) is always X11
+        if let Some(display_name) = display {
+            let src = gst::ElementFactory::make("ximagesrc")
+                .property("display-name", display_name)
+                .property("use-damage", false)
+                .property("show-pointer", show_pointer)
+                .property("do-timestamp", true)
+                .build()
+                .context("Failed to create ximagesrc for virtual display")?;
+
+            tracing::info!("Using Linux screen capture (ximagesrc) on display {}", display_name);
+            return Ok(src);
+        }
+
         // Try X11 capture if DISPLAY is set
         if std::env::var("DISPLAY").is_ok() {
             if let Ok(src) = gst::ElementFactory::make("ximagesrc")
                 .property("use-damage", false)
-                .property("show-pointer", true)
+                .property("show-pointer", show_pointer)
                 .property("do-timestamp", true)
                 .build()
             {
@@ -244,14 +717,14 @@ impl ScreenStreamer {
     }
 
     #[cfg(target_os = "windows")]
-    fn create_windows_capture(fps: u32) -> Result<gst::Element> {
+    fn create_windows_capture(fps: u32, show_pointer: bool) -> Result<gst::Element> {
         // dx9screencapsrc or d3d11screencapturesrc for Windows
         let src = gst::ElementFactory::make("d3d11screencapturesrc")
-            .property("show-cursor", true)
+            .property("show-cursor", show_pointer)
             .build()
             .or_else(|_| {
                 gst::ElementFactory::make("dx9screencapsrc")
-                    .property("cursor", true)
+                    .property("cursor", show_pointer)
                     .build()
             })
             .context("Failed to create Windows screen capture source")?;
@@ -261,33 +734,14 @@ impl ScreenStreamer {
     }
 
     /// Add audio capture pipeline (system audio → WebRTC)
-    fn add_audio_pipeline(pipeline: &gst::Pipeline, webrtcbin: &gst::Element) -> Result<()> {
-        // Audio source - platform specific
-        #[cfg(target_os = "macos")]
-        let audio_src = {
-            // On macOS, capturing system audio requires a virtual audio device
-            // like BlackHole, Soundflower, or similar. Try to use it if available.
-            gst::ElementFactory::make("osxaudiosrc")
-                .property("do-timestamp", true)
-                .build()
-                .context("osxaudiosrc not available - install BlackHole for system audio")?
-        };
-
-        #[cfg(target_os = "linux")]
-        let audio_src = {
-            gst::ElementFactory::make("pulsesrc")
-                .property("do-timestamp", true)
-                .build()
-                .or_else(|_| gst::ElementFactory::make("alsasrc").build())
-                .context("No audio source available")?
-        };
-
-        #[cfg(target_os = "windows")]
-        let audio_src = {
-            gst::ElementFactory::make("wasapisrc")
-                .property("do-timestamp", true)
-                .build()
-                .context("wasapisrc not available")?
+    fn add_audio_pipeline(
+        pipeline: &gst::Pipeline,
+        webrtcbin: &gst::Element,
+        source: &CaptureSource,
+    ) -> Result<()> {
+        let audio_src = match media_source::create_audio_source(source)? {
+            Some(src) => src,
+            None => Self::create_audio_capture()?,
         };
 
         // Audio conversion and resampling
@@ -349,6 +803,38 @@ impl ScreenStreamer {
         Ok(())
     }
 
+    /// System audio source - platform specific
+    fn create_audio_capture() -> Result<gst::Element> {
+        #[cfg(target_os = "macos")]
+        let audio_src = {
+            // On macOS, capturing system audio requires a virtual audio device
+            // like BlackHole, Soundflower, or similar. Try to use it if available.
+            gst::ElementFactory::make("osxaudiosrc")
+                .property("do-timestamp", true)
+                .build()
+                .context("osxaudiosrc not available - install BlackHole for system audio")?
+        };
+
+        #[cfg(target_os = "linux")]
+        let audio_src = {
+            gst::ElementFactory::make("pulsesrc")
+                .property("do-timestamp", true)
+                .build()
+                .or_else(|_| gst::ElementFactory::make("alsasrc").build())
+                .context("No audio source available")?
+        };
+
+        #[cfg(target_os = "windows")]
+        let audio_src = {
+            gst::ElementFactory::make("wasapisrc")
+                .property("do-timestamp", true)
+                .build()
+                .context("wasapisrc not available")?
+        };
+
+        Ok(audio_src)
+    }
+
     /// Set up handler for incoming audio from browser (mic → local speakers)
     fn setup_incoming_audio(pipeline: &gst::Pipeline, webrtcbin: &gst::Element) {
         let pipeline_weak = pipeline.downgrade();
@@ -408,66 +894,356 @@ impl ScreenStreamer {
         });
     }
 
-    fn create_encoder() -> Result<gst::Element> {
-        // Try hardware encoders first, then fall back to software
+    /// Crop element whose margins follow the shared capture region
+    fn create_crop(region: &Arc<Mutex<Option<CaptureRegion>>>) -> Result<gst::Element> {
+        let crop = gst::ElementFactory::make("videocrop").build()?;
+
+        // Margins depend on the frame size, so recompute them on every caps change
+        let region = region.clone();
+        let crop_weak = crop.downgrade();
+        crop.static_pad("sink")
+            .context("videocrop missing sink pad")?
+            .add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+                if let Some(gst::PadProbeData::Event(event)) = &info.data {
+                    if let gst::EventView::Caps(caps) = event.view() {
+                        if let (Some(crop), Some(size)) = (crop_weak.upgrade(), frame_size(caps.caps())) {
+                            apply_crop(&crop, size, *region.lock().unwrap());
+                        }
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
 
-        // macOS VideoToolbox
-        if let Ok(enc) = gst::ElementFactory::make("vtenc_h264")
-            .property("realtime", true)
-            .property("allow-frame-reordering", false)
-            .property("max-keyframe-interval", 30i32)
-            .build()
-        {
-            tracing::info!("Using VideoToolbox hardware encoder");
-            return Ok(enc);
+        Ok(crop)
+    }
+
+    /// Crop capture to a screen rectangle (`None` captures the whole screen)
+    pub fn set_capture_region(&self, region: Option<CaptureRegion>) -> Result<()> {
+        if region.is_some_and(|r| r.width == 0 || r.height == 0) {
+            anyhow::bail!("Capture region must not be empty");
         }
 
-        // NVIDIA NVENC
-        if let Ok(enc) = gst::ElementFactory::make("nvh264enc")
-            .property("preset", 1u32)  // low-latency
-            .property("rc-mode", 2u32) // CBR
-            .property("zerolatency", true)
-            .build()
-        {
-            tracing::info!("Using NVIDIA NVENC hardware encoder");
-            return Ok(enc);
+        match &self.local()?.crop {
+            Some(crop) => {
+                *self.region.lock().unwrap() = region;
+                let caps = crop.static_pad("sink").and_then(|pad| pad.current_caps());
+                if let Some(size) = caps.as_ref().and_then(|c| frame_size(c)) {
+                    apply_crop(crop, size, region);
+                }
+            }
+            None => {
+                self.stop_following_window();
+                self.restart_ximagesrc(0, region)?;
+                *self.region.lock().unwrap() = region;
+            }
         }
 
-        // Intel/AMD VAAPI
-        if let Ok(enc) = gst::ElementFactory::make("vaapih264enc")
-            .property("rate-control", 2u32) // CBR
-            .build()
-        {
-            tracing::info!("Using VAAPI hardware encoder");
-            return Ok(enc);
+        let mut transform = self.input_transform.lock().unwrap();
+        transform.origin_x = region.map_or(0, |r| r.x.max(0));
+        transform.origin_y = region.map_or(0, |r| r.y.max(0));
+        drop(transform);
+
+        *self.selected_monitor.lock().unwrap() = None;
+
+        match region {
+            Some(r) => tracing::info!("Capturing region {}x{}+{}+{}", r.width, r.height, r.x, r.y),
+            None => tracing::info!("Capturing whole screen"),
+        }
+        Ok(())
+    }
+
+    /// Point ximagesrc at a window (`0` for the root) and bounds, restarting
+    /// it so they take effect
+    fn restart_ximagesrc(&self, xid: u64, region: Option<CaptureRegion>) -> Result<()> {
+        // ximagesrc bounds are inclusive; all zeros means the whole screen
+        let (startx, starty, endx, endy) = match region {
+            Some(r) => {
+                let x = r.x.max(0) as u32;
+                let y = r.y.max(0) as u32;
+                (x, y, x + r.width - 1, y + r.height - 1)
+            }
+            None => (0, 0, 0, 0),
+        };
+
+        // The window and bounds are only read when the source starts
+        let capture_src = &self.local()?.capture_src;
+        capture_src.set_state(gst::State::Null)?;
+        capture_src.set_property("xid", xid);
+        capture_src.set_property("startx", startx);
+        capture_src.set_property("starty", starty);
+        capture_src.set_property("endx", endx);
+        capture_src.set_property("endy", endy);
+        capture_src.sync_state_with_parent()?;
+        Ok(())
+    }
+
+    /// Capture a single window and keep following it (`None` returns to the screen)
+    pub fn capture_window(self: &Arc<Self>, selector: Option<WindowSelector>) -> Result<()> {
+        if !is_ximagesrc(&self.local()?.capture_src) {
+            anyhow::bail!("Window capture requires X11 capture (ximagesrc)");
+        }
+
+        match selector {
+            Some(selector) => {
+                self.stop_following_window();
+                tracing::info!("Following window {:?}", selector);
+                let task = tokio::spawn(follow_window(Arc::downgrade(self), selector));
+                *self.window_follow.lock().unwrap() = Some(task.abort_handle());
+            }
+            None => self.set_capture_region(None)?,
         }
+        Ok(())
+    }
 
-        // Intel QuickSync
-        if let Ok(enc) = gst::ElementFactory::make("qsvh264enc").build() {
-            tracing::info!("Using Intel QuickSync encoder");
-            return Ok(enc);
+    fn stop_following_window(&self) {
+        if let Some(task) = self.window_follow.lock().unwrap().take() {
+            task.abort();
+        }
+        if self.captured_window.lock().unwrap().take().is_some() {
+            let _ = self.outgoing_tx.send(SignalingMessage::Window { window: None });
         }
+    }
 
-        // Software fallback (x264)
-        let enc = gst::ElementFactory::make("x264enc")
-            .property_from_str("tune", "zerolatency")
-            .property_from_str("speed-preset", "ultrafast")
-            .property("key-int-max", 30u32)
-            .property("bitrate", 4000u32)    // 4 Mbps
-            .build()
-            .context("No H.264 encoder available")?;
+    /// Apply a (re)located window: restart capture if it is a different
+    /// window or changed size, and move the input origin with it
+    fn update_window(&self, window: WindowInfo) -> Result<()> {
+        let mut current = self.captured_window.lock().unwrap();
+        if current.as_ref() == Some(&window) {
+            return Ok(());
+        }
 
-        tracing::info!("Using x264 software encoder");
-        Ok(enc)
+        let needs_restart = current
+            .as_ref()
+            .map_or(true, |c| c.id != window.id || c.width != window.width || c.height != window.height);
+        if needs_restart {
+            tracing::info!(
+                "Capturing window 0x{:x} {:?} ({}x{})",
+                window.id, window.title, window.width, window.height
+            );
+            self.restart_ximagesrc(window.id, None)?;
+            *self.region.lock().unwrap() = None;
+            *self.selected_monitor.lock().unwrap() = None;
+        }
+
+        let mut transform = self.input_transform.lock().unwrap();
+        transform.origin_x = window.x;
+        transform.origin_y = window.y;
+        drop(transform);
+
+        *current = Some(window.clone());
+        let _ = self.outgoing_tx.send(SignalingMessage::Window { window: Some(window) });
+        Ok(())
+    }
+
+    /// Send the titled windows of the captured display to the client
+    fn send_windows(&self) -> Result<()> {
+        let windows = list_windows(self.display.as_deref())?
+            .into_iter()
+            .filter(|w| w.title.is_some() && w.width > 1 && w.height > 1)
+            .collect();
+        let _ = self.outgoing_tx.send(SignalingMessage::Windows { windows });
+        Ok(())
+    }
+
+    /// Stream a single monitor by XRandR name (`None` for the whole screen)
+    pub fn select_monitor(&self, name: Option<&str>) -> Result<()> {
+        let region = match name {
+            Some(name) => {
+                let monitors = list_monitors(self.display.as_deref())?;
+                let monitor = monitors
+                    .iter()
+                    .find(|m| m.name == name)
+                    .with_context(|| format!("No monitor named '{}'", name))?;
+                Some(CaptureRegion {
+                    x: monitor.x,
+                    y: monitor.y,
+                    width: monitor.width,
+                    height: monitor.height,
+                })
+            }
+            None => None,
+        };
+
+        // Cropping to a region clears the selection, so set it afterwards
+        self.set_capture_region(region)?;
+        *self.selected_monitor.lock().unwrap() = name.map(str::to_string);
+        Ok(())
+    }
+
+    /// Send the monitor list and current selection to the client
+    fn send_displays(&self) -> Result<()> {
+        let displays = list_monitors(self.display.as_deref())?;
+        let selected = self.selected_monitor.lock().unwrap().clone();
+        let _ = self.outgoing_tx.send(SignalingMessage::Displays { displays, selected });
+        Ok(())
+    }
+
+    /// Switch the X screen to the viewport size so it streams at 1:1 pixels.
+    ///
+    /// Only applies while the whole screen is captured with ximagesrc.
+    fn resize_to_viewport(self: &Arc<Self>, width: u32, height: u32) {
+        let Ok(local) = self.local() else { return };
+        if !is_ximagesrc(&local.capture_src)
+            || self.region.lock().unwrap().is_some()
+            || self.captured_window.lock().unwrap().is_some()
+        {
+            return;
+        }
+
+        let (width, height) = match local.scaler.max_size() {
+            Some((max_width, max_height)) => (width.min(max_width), height.min(max_height)),
+            None => (width, height),
+        };
+
+        let streamer = Arc::downgrade(self);
+        let resize_lock = self.resize_lock.clone();
+        tokio::task::spawn_blocking(move || {
+            let _guard = resize_lock.lock().unwrap();
+            let Some(streamer) = streamer.upgrade() else { return };
+
+            match resize_screen(streamer.display.as_deref(), width, height) {
+                Ok(Some((width, height))) => {
+                    tracing::info!("Resized screen to {}x{}", width, height);
+                    // ximagesrc only reads the screen size when it starts
+                    if let Err(e) = streamer.restart_ximagesrc(0, None) {
+                        tracing::warn!("Failed to restart capture after resize: {}", e);
+                    }
+                    let _ = streamer.send_displays();
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Failed to resize screen to {}x{}: {}", width, height, e),
+            }
+        });
     }
 
-    /// Start the pipeline
-    pub fn start(&self) -> Result<()> {
-        self.pipeline.set_state(gst::State::Playing)?;
+    /// Map input to screen coordinates; `None` if it lands in a redacted area
+    pub fn map_input(&self, event: InputEvent) -> Option<InputEvent> {
+        let event = event.transform(&self.input_transform.lock().unwrap());
+        self.redactor.allows(&event).then_some(event)
+    }
+
+    /// Redacted areas in effect
+    pub fn redactions(&self) -> Redactions {
+        self.redactor.settings()
+    }
+
+    /// Change the redacted areas. Simulcast viewers share the capture, so
+    /// this applies to all of them.
+    pub fn set_redactions(&self, redactions: Redactions) {
+        self.redactor.set(redactions);
+    }
+
+    /// Start the pipeline and wait for the encoder to come up. Fails with
+    /// [`EncoderFailed`](crate::encoder::EncoderFailed) if the encoder
+    /// breaks on start, so the caller can rebuild with the next one.
+    pub async fn start(&self) -> Result<()> {
+        let state_change = self.pipeline.set_state(gst::State::Playing);
+
+        // An encoder error explains a failed state change best, check it first
+        if let VideoSource::Local(local) = &self.video {
+            let pipeline = self.pipeline.clone();
+            let encoder = local.encoder.clone();
+            tokio::task::spawn_blocking(move || await_startup(&pipeline, &[&encoder])).await??;
+        }
+        state_change?;
         tracing::info!("Screen capture pipeline started");
+
+        if self.congestion.adaptive {
+            let task = match &self.video {
+                VideoSource::Local(local) => congestion::spawn_control_loop(
+                    &self.congestion,
+                    self.webrtcbin.clone(),
+                    local.encoder.clone(),
+                    local.scaler.clone(),
+                    self.bandwidth_estimate.clone(),
+                ),
+                VideoSource::Simulcast(feed) => simulcast::spawn_layer_control(
+                    &self.congestion,
+                    self.webrtcbin.clone(),
+                    feed.clone(),
+                    self.bandwidth_estimate.clone(),
+                ),
+            };
+            if let Some(old) = self.congestion_task.lock().unwrap().replace(task) {
+                old.abort();
+            }
+        }
+
+        if let Some(probe) = &self.latency {
+            let task = latency::spawn_reporter(probe.clone(), self.outgoing_tx.clone());
+            if let Some(old) = self.latency_task.lock().unwrap().replace(task) {
+                old.abort();
+            }
+        }
+
+        // The channel has to exist before the offer is created
+        if self.client_cursor {
+            let channel = self.webrtcbin.emit_by_name::<Option<gst_webrtc::WebRTCDataChannel>>(
+                "create-data-channel",
+                &[&"cursor", &None::<gst::Structure>],
+            );
+            match channel {
+                Some(channel) => {
+                    let task = cursor::spawn_tracker(self.display.clone(), channel, self.input_transform.clone());
+                    if let Some(old) = self.cursor_task.lock().unwrap().replace(task) {
+                        old.abort();
+                    }
+                }
+                None => tracing::warn!("Cannot create cursor data channel — check that the sctp plugin is loaded"),
+            }
+        }
         Ok(())
     }
 
+    pub fn codec(&self) -> Codec {
+        self.video.codec()
+    }
+
+    /// Settings of the session's own encoder (`None` with simulcast)
+    pub fn encoder_settings(&self) -> Option<EncoderSettings> {
+        self.local().ok().map(|local| local.encoder.settings())
+    }
+
+    /// Latest glass-to-glass latency report, if measuring
+    pub fn latency(&self) -> Option<LatencyReport> {
+        self.latency.as_ref()?.last_report()
+    }
+
+    /// Pipeline graph in DOT format, with negotiated caps and non-default
+    /// element properties such as the encoder's
+    pub fn pipeline_dot(&self) -> String {
+        self.pipeline.debug_to_dot_data(gst::DebugGraphDetails::all()).to_string()
+    }
+
+    /// Resolves when the started pipeline fails
+    pub fn watch_bus(&self) -> impl std::future::Future<Output = PipelineFailure> + Send + 'static {
+        let failed = bus_monitor::watch(&self.pipeline);
+        // A failed simulcast hub stops this session's video just the same
+        let hub_failed = match &self.video {
+            VideoSource::Simulcast(feed) => Some(feed.hub_failed()),
+            VideoSource::Local(_) => None,
+        };
+        async move {
+            let Some(hub_failed) = hub_failed else { return failed.await };
+            tokio::select! {
+                failure = failed => failure,
+                failure = hub_failed => failure,
+            }
+        }
+    }
+
+    /// Client requests that changed this session's capture area, viewport
+    /// or encoder settings, to replay on a rebuilt pipeline
+    pub fn client_changes(&self) -> Vec<SignalingMessage> {
+        let changes = self.client_changes.lock().unwrap();
+        let mut messages: Vec<_> = changes.placement.iter().chain(&changes.viewport).cloned().collect();
+        let encoder = changes.encoder;
+        if encoder.bitrate.is_some() || encoder.fps.is_some() || encoder.keyframe_interval.is_some() || encoder.preset.is_some() {
+            messages.push(SignalingMessage::SetEncoder { update: encoder });
+        }
+        messages
+    }
+
     /// Create and send an SDP offer
     pub fn create_offer(&self) {
         let webrtcbin = self.webrtcbin.clone();
@@ -505,7 +1281,7 @@ impl ScreenStreamer {
     }
 
     /// Handle incoming signaling message
-    pub fn handle_signaling(&self, msg: SignalingMessage) -> Result<()> {
+    pub fn handle_signaling(self: &Arc<Self>, msg: SignalingMessage) -> Result<()> {
         match msg {
             SignalingMessage::Answer { sdp } => {
                 tracing::info!("Received SDP answer");
@@ -522,14 +1298,104 @@ impl ScreenStreamer {
                 sdp_mid,
                 sdp_m_line_index,
             } => {
+                if !self.ice.accepts_remote(&candidate) {
+                    tracing::debug!("Ignoring remote ICE candidate {}", candidate);
+                    return Ok(());
+                }
                 let sdp_m_line_index = sdp_m_line_index.unwrap_or(0);
                 self.webrtcbin.emit_by_name::<()>(
                     "add-ice-candidate",
                     &[&sdp_m_line_index, &candidate],
                 );
             }
-            SignalingMessage::Offer { .. } => {
-                // Server doesn't receive offers
+            SignalingMessage::ListDisplays => {
+                self.send_displays()?;
+            }
+            SignalingMessage::SelectDisplay { name } => {
+                self.select_monitor(name.as_deref())?;
+                self.send_displays()?;
+                self.client_changes.lock().unwrap().placement = Some(SignalingMessage::SelectDisplay { name });
+            }
+            SignalingMessage::SetRegion { region } => {
+                self.set_capture_region(region)?;
+                let _ = self.outgoing_tx.send(SignalingMessage::Region { region });
+                self.client_changes.lock().unwrap().placement = Some(SignalingMessage::SetRegion { region });
+            }
+            SignalingMessage::Viewport { width, height } => {
+                // Simulcast layers are shared, so they ignore viewports
+                if let VideoSource::Local(local) = &self.video {
+                    local.scaler.set_viewport(width, height);
+                    if self.resize_display {
+                        self.resize_to_viewport(width, height);
+                    }
+                }
+                self.client_changes.lock().unwrap().viewport = Some(SignalingMessage::Viewport { width, height });
+            }
+            SignalingMessage::Capabilities { .. } => {
+                // Only considered before the streamer is created
+            }
+            SignalingMessage::Refresh => match &self.video {
+                VideoSource::Local(local) => {
+                    if let Some(keyframes) = &local.keyframes {
+                        keyframes.request();
+                    }
+                }
+                VideoSource::Simulcast(feed) => feed.request_keyframe(),
+            },
+            SignalingMessage::GetEncoder => {
+                let settings = self.local()?.encoder.settings();
+                let _ = self.outgoing_tx.send(SignalingMessage::Encoder { settings });
+            }
+            SignalingMessage::SetEncoder { update } => {
+                let local = self.local()?;
+                let bitrates = self.congestion.min_kbps..=self.congestion.max_kbps;
+                let settings = local.encoder.update(&update, bitrates);
+                if update.fps.is_some() {
+                    local.scaler.set_fps(settings.fps);
+                }
+                let mut changes = self.client_changes.lock().unwrap();
+                changes.encoder = EncoderUpdate {
+                    bitrate: update.bitrate.or(changes.encoder.bitrate),
+                    fps: update.fps.or(changes.encoder.fps),
+                    keyframe_interval: update.keyframe_interval.or(changes.encoder.keyframe_interval),
+                    preset: update.preset.or(changes.encoder.preset),
+                };
+                drop(changes);
+                let _ = self.outgoing_tx.send(SignalingMessage::Encoder { settings });
+            }
+            SignalingMessage::ListWindows => {
+                self.send_windows()?;
+            }
+            SignalingMessage::SelectWindow { window } => {
+                let selector = match window.as_deref() {
+                    Some(s) => Some(WindowSelector::parse(s).context("Invalid window selector")?),
+                    None => None,
+                };
+                self.capture_window(selector)?;
+                self.client_changes.lock().unwrap().placement = Some(SignalingMessage::SelectWindow { window });
+            }
+            SignalingMessage::LatencyPong { server_time, client_time } => {
+                if let Some(probe) = &self.latency {
+                    probe.pong(server_time, client_time);
+                }
+            }
+            SignalingMessage::FrameDisplayed { rtp_timestamp, received_at, displayed_at } => {
+                if let Some(probe) = &self.latency {
+                    probe.frame_displayed(rtp_timestamp, received_at, displayed_at);
+                }
+            }
+            SignalingMessage::Offer { .. }
+            | SignalingMessage::SessionEnded { .. }
+            | SignalingMessage::Displays { .. }
+            | SignalingMessage::Region { .. }
+            | SignalingMessage::Windows { .. }
+            | SignalingMessage::Window { .. }
+            | SignalingMessage::Encoder { .. }
+            | SignalingMessage::StreamError { .. }
+            | SignalingMessage::IceServers { .. }
+            | SignalingMessage::LatencyPing { .. }
+            | SignalingMessage::Latency { .. } => {
+                // Server doesn't receive offers or server notices
             }
         }
         Ok(())
@@ -543,8 +1409,134 @@ impl ScreenStreamer {
     }
 }
 
+/// Keep looking up a window so capture follows it as it moves, resizes or
+/// gets recreated
+async fn follow_window(streamer: Weak<ScreenStreamer>, selector: WindowSelector) {
+    let mut interval = tokio::time::interval(WINDOW_POLL_INTERVAL);
+    let mut missing = false;
+
+    loop {
+        interval.tick().await;
+        let Some(streamer) = streamer.upgrade() else { break };
+
+        let display = streamer.display.clone();
+        let windows = match tokio::task::spawn_blocking(move || list_windows(display.as_deref())).await {
+            Ok(Ok(windows)) => windows,
+            Ok(Err(e)) => {
+                tracing::warn!("Window lookup failed: {}", e);
+                continue;
+            }
+            Err(_) => continue,
+        };
+
+        // Stick with the window we already capture while it still matches
+        let current_id = streamer.captured_window.lock().unwrap().as_ref().map(|w| w.id);
+        let found = current_id
+            .and_then(|id| windows.iter().find(|w| w.id == id && selector.matches(w)).cloned())
+            .or_else(|| selector.resolve(&windows));
+
+        match found {
+            Some(window) => {
+                missing = false;
+                if let Err(e) = streamer.update_window(window) {
+                    tracing::warn!("Failed to follow window: {}", e);
+                }
+            }
+            None if !missing => {
+                missing = true;
+                tracing::warn!("Window {:?} not found, waiting for it to appear", selector);
+            }
+            None => {}
+        }
+    }
+}
+
+fn is_ximagesrc(element: &gst::Element) -> bool {
+    element.factory().is_some_and(|f| f.name() == "ximagesrc")
+}
+
+/// Width and height of raw video caps
+fn frame_size(caps: &gst::CapsRef) -> Option<(i32, i32)> {
+    let s = caps.structure(0)?;
+    Some((s.get::<i32>("width").ok()?, s.get::<i32>("height").ok()?))
+}
+
+/// Set videocrop margins so only `region` of a `size` frame remains
+fn apply_crop(crop: &gst::Element, size: (i32, i32), region: Option<CaptureRegion>) {
+    let (left, top, right, bottom) = match region {
+        Some(r) => {
+            let left = r.x.clamp(0, size.0);
+            let top = r.y.clamp(0, size.1);
+            let right = (size.0 - left - r.width as i32).max(0);
+            let bottom = (size.1 - top - r.height as i32).max(0);
+            (left, top, right, bottom)
+        }
+        None => (0, 0, 0, 0),
+    };
+
+    crop.set_property("left", left);
+    crop.set_property("top", top);
+    crop.set_property("right", right);
+    crop.set_property("bottom", bottom);
+}
+
 impl Drop for ScreenStreamer {
     fn drop(&mut self) {
+        if let Some(task) = self.window_follow.get_mut().unwrap().take() {
+            task.abort();
+        }
+        if let Some(task) = self.congestion_task.get_mut().unwrap().take() {
+            task.abort();
+        }
+        if let Some(task) = self.latency_task.get_mut().unwrap().take() {
+            task.abort();
+        }
+        if let Some(task) = self.cursor_task.get_mut().unwrap().take() {
+            task.abort();
+        }
+        if let Some(task) = self.watermark_task.take() {
+            task.abort();
+        }
         let _ = self.pipeline.set_state(gst::State::Null);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: i32, y: i32, width: u32, height: u32) -> Option<CaptureRegion> {
+        Some(CaptureRegion { x, y, width, height })
+    }
+
+    #[test]
+    fn parses_geometry() {
+        assert_eq!(CaptureRegion::parse("800x600+10+20"), region(10, 20, 800, 600));
+        assert_eq!(CaptureRegion::parse(" 1920X1080+0+0 "), region(0, 0, 1920, 1080));
+    }
+
+    #[test]
+    fn parses_negative_offsets() {
+        assert_eq!(CaptureRegion::parse("800x600-10-20"), region(-10, -20, 800, 600));
+        assert_eq!(CaptureRegion::parse("800x600+1920-100"), region(1920, -100, 800, 600));
+        assert_eq!(CaptureRegion::parse("800x600-1920+100"), region(-1920, 100, 800, 600));
+    }
+
+    #[test]
+    fn rejects_malformed_geometry() {
+        for input in [
+            "",
+            "800x600",
+            "800x600+10",
+            "0x600+0+0",
+            "800x+0+0",
+            "axb+0+0",
+            "800x600+a+0",
+            "800x600+0+b",
+            "800x600++10+0",
+            "+10+20",
+        ] {
+            assert_eq!(CaptureRegion::parse(input), None, "{:?}", input);
+        }
+    }
+}
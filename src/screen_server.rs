@@ -1,105 +1,291 @@
 //! HTTP and WebSocket server for screen streaming
 
-use crate::input::{InputController, InputEvent};
-use crate::screen_capture::{ScreenStreamer, SignalingMessage};
-use anyhow::Result;
+use crate::admin;
+use crate::app_session::{AppConfig, AppSession};
+use crate::auth::Auth;
+use crate::bus_monitor::RestartBackoff;
+use crate::desktop::{DesktopLease, DesktopManager};
+use crate::encoder::{EncoderFailed, QualityProfile};
+use crate::input::{start_input_thread, InputEvent};
+use crate::monitors::list_monitors;
+use crate::redaction::Redactions;
+use crate::screen_capture::{ScreenStreamer, SignalingMessage, StreamConfig};
+use crate::sessions::Sessions;
+use crate::simulcast::{SimulcastHub, SimulcastShare};
+use crate::watermark::Viewer;
+use anyhow::{Context, Result};
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        State, WebSocketUpgrade,
+        Query, State, WebSocketUpgrade,
     },
-    response::{Html, IntoResponse},
-    routing::get,
-    Router,
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
 };
-use futures::{SinkExt, StreamExt};
-use std::sync::Arc;
+use futures::stream::SplitStream;
+use futures::{SinkExt, Stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::sync::mpsc;
 use tower_http::cors::CorsLayer;
 
-/// Start input handling thread and return sender
-fn start_input_thread() -> mpsc::UnboundedSender<InputEvent> {
-    let (tx, mut rx) = mpsc::unbounded_channel::<InputEvent>();
+/// How long to wait for a client to announce its codecs
+const CAPABILITIES_TIMEOUT: Duration = Duration::from_secs(1);
 
-    // Spawn a blocking thread for input handling (Enigo is not Send)
-    std::thread::spawn(move || {
-        let controller = InputController::new();
-        while let Some(event) = rx.blocking_recv() {
-            controller.handle_event(&event);
-        }
-    });
+/// Shared application state
+pub struct AppState {
+    pub stream: StreamConfig,
+    /// Application streaming mode: one app + virtual display per session
+    pub app: Option<AppConfig>,
+    /// Per-user desktop mode: one virtual display per authenticated user
+    pub desktops: Option<Arc<DesktopManager>>,
+    /// Shared multi-layer encodes for viewers of the server-wide display
+    pub simulcast: Option<SimulcastShare>,
+    pub auth: Auth,
+    /// Live sessions, for the admin endpoints
+    pub sessions: Arc<Sessions>,
+    /// Redactions for new sessions, changed through the admin endpoints
+    pub redactions: Mutex<Redactions>,
+}
 
-    tx
+/// What a session streams and sends input to
+enum SessionTarget {
+    /// The server-wide display (`$DISPLAY` or the headless display)
+    Shared,
+    /// A freshly launched application on its own display
+    App(AppSession),
+    /// The user's persistent desktop
+    Desktop(DesktopLease),
 }
 
-/// Shared application state
-pub struct AppState {
-    pub fps: u32,
+impl SessionTarget {
+    /// Pick the target for a new session according to the server mode
+    async fn resolve(state: &AppState, user: Option<String>) -> Result<Self> {
+        if let Some(app) = state.app.clone() {
+            let session = tokio::task::spawn_blocking(move || AppSession::launch(&app)).await??;
+            return Ok(Self::App(session));
+        }
+
+        if let Some(desktops) = state.desktops.clone() {
+            let user = user.context("Per-user desktops require authentication")?;
+            let lease = tokio::task::spawn_blocking(move || desktops.acquire(&user)).await??;
+            return Ok(Self::Desktop(lease));
+        }
+
+        Ok(Self::Shared)
+    }
+
+    /// X display to capture, falling back to the server-wide one
+    fn display(&self, shared: Option<&str>) -> Option<String> {
+        match self {
+            Self::Shared => shared.map(str::to_string),
+            Self::App(session) => Some(session.display_name().to_string()),
+            Self::Desktop(lease) => Some(lease.display_name().to_string()),
+        }
+    }
+
+    fn app_session(&self) -> Option<&AppSession> {
+        match self {
+            Self::App(session) => Some(session),
+            _ => None,
+        }
+    }
+
+    /// Tear the target down off the async workers: stopping an application
+    /// and its X server blocks for seconds
+    async fn release(self) {
+        let _ = tokio::task::spawn_blocking(move || drop(self)).await;
+    }
 }
 
 /// Run the HTTP/WebSocket server
-pub async fn run_server(fps: u32, port: u16) -> Result<()> {
-    let state = Arc::new(AppState { fps });
+pub async fn run_server(state: AppState, port: u16) -> Result<()> {
+    let state = Arc::new(state);
+
+    if let Some(desktops) = &state.desktops {
+        desktops.spawn_reaper();
+    }
 
     let app = Router::new()
         .route("/", get(index_handler))
         .route("/ws", get(ws_handler))
+        .route("/api/displays", get(displays_handler))
+        .route("/api/token", post(token_handler))
         .layer(CorsLayer::permissive())
+        // Added after the layer so admin routes stay same-origin only
+        .merge(admin::routes(state.clone()))
         .with_state(state);
 
     let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
     tracing::info!("Server listening on http://{}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
 
     Ok(())
 }
 
+/// Resolve on Ctrl-C or SIGTERM so owned resources (e.g. a virtual display) get cleaned up
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("Shutting down");
+}
+
+/// Response asking the browser for Basic credentials
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"streamio\"")],
+        "Authentication required",
+    )
+        .into_response()
+}
+
 /// Serve the client HTML page
-async fn index_handler() -> Html<&'static str> {
-    Html(include_str!("../client/screen.html"))
+async fn index_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if state.auth.enabled() && state.auth.authenticate(&headers).is_none() {
+        return unauthorized();
+    }
+    Html(include_str!("../client/screen.html")).into_response()
+}
+
+/// List the monitors of the server-wide display
+async fn displays_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if state.auth.enabled() && state.auth.authenticate(&headers).is_none() {
+        return unauthorized();
+    }
+
+    let display = state.stream.display.clone();
+    match tokio::task::spawn_blocking(move || list_monitors(display.as_deref())).await {
+        Ok(Ok(monitors)) => Json(monitors).into_response(),
+        Ok(Err(e)) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Trade Basic credentials for a single-use `/ws` token; `null` when auth is disabled
+async fn token_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if !state.auth.enabled() {
+        return Json(serde_json::json!({ "token": null })).into_response();
+    }
+    match state.auth.authenticate(&headers) {
+        Some(user) => Json(serde_json::json!({ "token": state.auth.issue_token(&user) })).into_response(),
+        None => unauthorized(),
+    }
 }
 
 /// Handle WebSocket connections
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_websocket(socket, state))
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let user = state.auth.authenticate_socket(&headers, &query);
+    if state.auth.enabled() && user.is_none() {
+        return unauthorized();
+    }
+    ws.on_upgrade(move |socket| handle_websocket(socket, state, user))
 }
 
 /// Handle a WebSocket session
-async fn handle_websocket(socket: WebSocket, state: Arc<AppState>) {
-    tracing::info!("New WebSocket connection");
+async fn handle_websocket(mut socket: WebSocket, state: Arc<AppState>, user: Option<String>) {
+    match &user {
+        Some(user) => tracing::info!("New WebSocket connection from user '{}'", user),
+        None => tracing::info!("New WebSocket connection"),
+    }
 
-    let (mut ws_tx, mut ws_rx) = socket.split();
+    let session = state.sessions.register(user.clone());
+    tracing::info!("Session {} started", session.id());
+    let viewer = Viewer {
+        user: user.clone(),
+        session: session.id(),
+    };
+
+    // Resolve which display this session streams and controls
+    let target = match SessionTarget::resolve(&state, user).await {
+        Ok(target) => target,
+        Err(e) => {
+            tracing::error!("Failed to set up session: {}", e);
+            let msg = SignalingMessage::SessionEnded { reason: e.to_string() };
+            let json = serde_json::to_string(&msg).unwrap();
+            let _ = socket.send(Message::Text(json.into())).await;
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
+    };
+
+    let (mut ws_tx, ws_rx) = socket.split();
+
+    // Clients announce the codecs they decode first; messages that arrive
+    // before that are replayed once the streamer exists
+    let (capabilities, mut ws_rx) = receive_capabilities(ws_rx).await;
+
+    let mut stream = state.stream.clone();
+    stream.display = target.display(state.stream.display.as_deref());
+    if let Some(capabilities) = capabilities {
+        stream.client_codecs = Some(capabilities.codecs);
+        stream.client_codecs_444 = capabilities.codecs_444;
+        stream.profile = capabilities.profile.unwrap_or(stream.profile);
+    }
+    stream.redactions = state.redactions.lock().unwrap().clone();
+    stream.viewer = viewer;
 
     // Channel for outgoing signaling messages
     let (sig_tx, mut sig_rx) = mpsc::unbounded_channel::<SignalingMessage>();
+    let session_tx = sig_tx.clone();
 
-    // Create screen streamer
-    let streamer = match ScreenStreamer::new(state.fps, sig_tx) {
-        Ok(s) => Arc::new(s),
+    let simulcast = shared_hub(&state, &target, &stream).await;
+    let mut streamer = match start_streamer(&stream, simulcast, sig_tx.clone()).await {
+        Ok(streamer) => streamer,
         Err(e) => {
-            tracing::error!("Failed to create screen streamer: {}", e);
+            tracing::error!("{}", e);
+            target.release().await;
             return;
         }
     };
-
-    // Start the pipeline
-    if let Err(e) = streamer.start() {
-        tracing::error!("Failed to start pipeline: {}", e);
-        return;
-    }
+    session.set_streamer(&streamer);
+    let mut pipeline_failed = Box::pin(streamer.watch_bus());
+    let mut backoff = RestartBackoff::default();
+    let mut restart: Option<std::pin::Pin<Box<tokio::time::Sleep>>> = None;
 
     // Task to forward outgoing signaling messages to WebSocket
-    let ws_forward_task = tokio::spawn(async move {
+    let mut ws_forward_task = tokio::spawn(async move {
         while let Some(msg) = sig_rx.recv().await {
             let json = serde_json::to_string(&msg).unwrap();
             if ws_tx.send(Message::Text(json.into())).await.is_err() {
                 break;
             }
+            if matches!(msg, SignalingMessage::SessionEnded { .. }) {
+                let _ = ws_tx.send(Message::Close(None)).await;
+                break;
+            }
         }
     });
 
@@ -110,18 +296,92 @@ async fn handle_websocket(socket: WebSocket, state: Arc<AppState>) {
         streamer_offer.create_offer();
     });
 
-    // Start input handling on dedicated thread
-    let input_tx = start_input_thread();
+    // Desktops keep their input thread across sessions; otherwise start one
+    let input_tx = match &target {
+        SessionTarget::Desktop(lease) => lease.input_tx(),
+        _ => start_input_thread(stream.display.clone()),
+    };
+
+    // In application mode the session lives only as long as the app
+    let mut app_exit = Box::pin(async {
+        match target.app_session() {
+            Some(session) => session.wait_exit().await,
+            None => std::future::pending().await,
+        }
+    });
+    let mut ended_by_server = false;
+
+    loop {
+        let msg = tokio::select! {
+            msg = ws_rx.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            status = &mut app_exit => {
+                tracing::info!("Application exited ({}), ending session", status);
+                let _ = session_tx.send(SignalingMessage::SessionEnded {
+                    reason: format!("Application exited ({})", status),
+                });
+                ended_by_server = true;
+                break;
+            }
+            failure = &mut pipeline_failed, if restart.is_none() => {
+                let _ = session_tx.send(SignalingMessage::StreamError {
+                    reason: failure.to_string(),
+                    restarting: stream.auto_restart,
+                });
+                if !stream.auto_restart {
+                    let _ = session_tx.send(SignalingMessage::SessionEnded {
+                        reason: format!("Stream failed: {}", failure),
+                    });
+                    ended_by_server = true;
+                    break;
+                }
 
-    // Handle incoming WebSocket messages
-    let streamer_msg = streamer.clone();
+                let delay = backoff.next_delay();
+                tracing::warn!("Restarting pipeline in {:?}", delay);
+                if let Err(e) = streamer.stop() {
+                    tracing::error!("Failed to stop streamer: {}", e);
+                }
+                restart = Some(Box::pin(tokio::time::sleep(delay)));
+                continue;
+            }
+            _ = async { restart.as_mut().unwrap().await }, if restart.is_some() => {
+                restart = None;
+                // Keep redactions changed for this session while it ran, and
+                // take a fresh simulcast hub if the old one failed
+                stream.redactions = streamer.redactions();
+                let simulcast = shared_hub(&state, &target, &stream).await;
+                match start_streamer(&stream, simulcast, sig_tx.clone()).await {
+                    Ok(restarted) => {
+                        // Replay the client's capture, viewport and encoder changes
+                        for msg in streamer.client_changes() {
+                            if let Err(e) = restarted.handle_signaling(msg) {
+                                tracing::warn!("Cannot restore session setting: {}", e);
+                            }
+                        }
+                        streamer = restarted;
+                        session.set_streamer(&streamer);
+                        pipeline_failed = Box::pin(streamer.watch_bus());
+                        backoff.restarted();
+                        streamer.create_offer();
+                        tracing::info!("Pipeline restarted");
+                    }
+                    Err(e) => {
+                        let delay = backoff.next_delay();
+                        tracing::warn!("{}, retrying in {:?}", e, delay);
+                        restart = Some(Box::pin(tokio::time::sleep(delay)));
+                    }
+                }
+                continue;
+            }
+        };
 
-    while let Some(msg) = ws_rx.next().await {
         match msg {
             Ok(Message::Text(text)) => {
                 // Try to parse as signaling message
                 if let Ok(sig_msg) = serde_json::from_str::<SignalingMessage>(&text) {
-                    if let Err(e) = streamer_msg.handle_signaling(sig_msg) {
+                    if let Err(e) = streamer.handle_signaling(sig_msg) {
                         tracing::error!("Signaling error: {}", e);
                     }
                     continue;
@@ -129,7 +389,9 @@ async fn handle_websocket(socket: WebSocket, state: Arc<AppState>) {
 
                 // Try to parse as input event
                 if let Ok(input_event) = serde_json::from_str::<InputEvent>(&text) {
-                    let _ = input_tx.send(input_event);
+                    if let Some(event) = streamer.map_input(input_event) {
+                        let _ = input_tx.send(event);
+                    }
                     continue;
                 }
 
@@ -148,10 +410,108 @@ async fn handle_websocket(socket: WebSocket, state: Arc<AppState>) {
     }
 
     // Cleanup
+    if ended_by_server {
+        // Let the client see why the session ended before closing the socket
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(1), &mut ws_forward_task).await;
+    }
     ws_forward_task.abort();
     if let Err(e) = streamer.stop() {
         tracing::error!("Failed to stop streamer: {}", e);
     }
+    drop(app_exit);
+    target.release().await;
 
     tracing::info!("WebSocket session ended");
 }
+
+/// Simulcast hub for the session, if it streams the server-wide display and
+/// can share the simulcast encodes: not if it needs its own watermark or
+/// text encoding, or cannot decode the simulcast codec
+async fn shared_hub(state: &AppState, target: &SessionTarget, stream: &StreamConfig) -> Option<Arc<SimulcastHub>> {
+    let own_encode = stream.watermark.is_some() || stream.profile != QualityProfile::Standard;
+    match (&state.simulcast, target) {
+        (Some(share), SessionTarget::Shared) if !own_encode => match share.acquire(stream).await {
+            Ok(hub) if hub.supports(stream.client_codecs.as_deref()) => Some(hub),
+            Ok(hub) => {
+                tracing::info!(
+                    "Client cannot decode simulcast {}, encoding separately",
+                    hub.codec().encoding_name()
+                );
+                None
+            }
+            Err(e) => {
+                tracing::warn!("Simulcast unavailable, encoding separately: {}", e);
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Create and start a screen streamer, rebuilding it with the next encoder
+/// if one fails on start
+async fn start_streamer(
+    stream: &StreamConfig,
+    simulcast: Option<Arc<SimulcastHub>>,
+    sig_tx: mpsc::UnboundedSender<SignalingMessage>,
+) -> Result<Arc<ScreenStreamer>> {
+    loop {
+        let streamer = ScreenStreamer::new(stream, simulcast.clone(), sig_tx.clone())
+            .map(Arc::new)
+            .context("Failed to create screen streamer")?;
+
+        if let Some(selector) = stream.window.clone() {
+            if let Err(e) = streamer.capture_window(Some(selector)) {
+                tracing::warn!("Cannot capture window: {}", e);
+            }
+        }
+
+        match streamer.start().await {
+            Ok(()) => return Ok(streamer),
+            Err(e) if e.is::<EncoderFailed>() => {
+                tracing::warn!("{}, falling back to the next encoder", e);
+            }
+            Err(e) => return Err(e.context("Failed to start pipeline")),
+        }
+    }
+}
+
+/// What a client announced in its `capabilities` message
+#[derive(Debug, Clone)]
+pub struct ClientCapabilities {
+    pub codecs: Vec<String>,
+    pub codecs_444: Vec<String>,
+    pub profile: Option<QualityProfile>,
+}
+
+/// Wait for the client's `capabilities` message. Returns what it announced
+/// (`None` for clients that don't say) and the socket stream with any
+/// other messages received meanwhile put back in front.
+async fn receive_capabilities(
+    mut ws_rx: SplitStream<WebSocket>,
+) -> (
+    Option<ClientCapabilities>,
+    impl Stream<Item = Result<Message, axum::Error>> + Unpin,
+) {
+    let deadline = tokio::time::Instant::now() + CAPABILITIES_TIMEOUT;
+    let mut early = Vec::new();
+    let mut capabilities = None;
+
+    while let Ok(Some(msg)) = tokio::time::timeout_at(deadline, ws_rx.next()).await {
+        if let Ok(Message::Text(text)) = &msg {
+            if let Ok(SignalingMessage::Capabilities { codecs, codecs_444, profile }) = serde_json::from_str(text) {
+                tracing::info!("Client decodes {} (4:4:4: {})", codecs.join(", "), codecs_444.join(", "));
+                capabilities = Some(ClientCapabilities { codecs, codecs_444, profile });
+                break;
+            }
+        }
+
+        let closed = matches!(msg, Ok(Message::Close(_)) | Err(_));
+        early.push(msg);
+        if closed {
+            break;
+        }
+    }
+
+    (capabilities, futures::stream::iter(early).chain(ws_rx))
+}
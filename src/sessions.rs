@@ -0,0 +1,114 @@
+//! Registry of live streaming sessions
+//!
+//! Every WebSocket session gets a numeric id and is listed here while it
+//! runs, so admin endpoints can inspect its pipeline. Entries hold the
+//! streamer weakly and follow it across pipeline restarts.
+
+use crate::encoder::EncoderSettings;
+use crate::latency::LatencyReport;
+use crate::screen_capture::ScreenStreamer;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+struct SessionEntry {
+    user: Option<String>,
+    started: SystemTime,
+    streamer: Weak<ScreenStreamer>,
+}
+
+/// What the admin session list shows
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSummary {
+    pub id: u64,
+    pub user: Option<String>,
+    /// Unix time in seconds
+    pub started_at: u64,
+    pub codec: Option<String>,
+    /// Settings of the session's own encoder (`None` for simulcast viewers)
+    pub encoder: Option<EncoderSettings>,
+    /// Latest latency measurement, with `LATENCY_PROBE`
+    pub latency: Option<LatencyReport>,
+}
+
+/// Live sessions by id
+#[derive(Default)]
+pub struct Sessions {
+    next_id: AtomicU64,
+    live: Mutex<BTreeMap<u64, SessionEntry>>,
+}
+
+impl Sessions {
+    /// Add a session; it is removed when the handle is dropped
+    pub fn register(self: &Arc<Self>, user: Option<String>) -> SessionHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.live.lock().unwrap().insert(
+            id,
+            SessionEntry {
+                user,
+                started: SystemTime::now(),
+                streamer: Weak::new(),
+            },
+        );
+        SessionHandle {
+            id,
+            sessions: self.clone(),
+        }
+    }
+
+    pub fn list(&self) -> Vec<SessionSummary> {
+        self.live
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, entry)| {
+                let streamer = entry.streamer.upgrade();
+                SessionSummary {
+                    id,
+                    user: entry.user.clone(),
+                    started_at: entry.started.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                    codec: streamer.as_ref().map(|s| s.codec().encoding_name().to_string()),
+                    encoder: streamer.as_ref().and_then(|s| s.encoder_settings()),
+                    latency: streamer.as_ref().and_then(|s| s.latency()),
+                }
+            })
+            .collect()
+    }
+
+    /// The session's current streamer, if it has one running
+    pub fn streamer(&self, id: u64) -> Option<Arc<ScreenStreamer>> {
+        self.live.lock().unwrap().get(&id)?.streamer.upgrade()
+    }
+
+    /// Running streamers of all sessions
+    pub fn streamers(&self) -> Vec<Arc<ScreenStreamer>> {
+        self.live.lock().unwrap().values().filter_map(|entry| entry.streamer.upgrade()).collect()
+    }
+}
+
+/// A registered session
+pub struct SessionHandle {
+    id: u64,
+    sessions: Arc<Sessions>,
+}
+
+impl SessionHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Point the entry at a new or rebuilt streamer
+    pub fn set_streamer(&self, streamer: &Arc<ScreenStreamer>) {
+        if let Some(entry) = self.sessions.live.lock().unwrap().get_mut(&self.id) {
+            entry.streamer = Arc::downgrade(streamer);
+        }
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        self.sessions.live.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,496 @@
+//! Simulcast: shared encodes at several qualities
+//!
+//! Without simulcast every viewer gets its own capture and encode. With it,
+//! the shared display is captured once and encoded as a few layers of
+//! decreasing resolution and bitrate. Each viewer's session forwards one
+//! layer to its `webrtcbin`, picked from that viewer's bandwidth estimate,
+//! and changes layer only on a keyframe of the new one so the decoder never
+//! sees a delta frame without its reference.
+//!
+//! The hub's bus is watched like a session's: when it fails, every session
+//! fed from it is told through [`SimulcastHub::failed`], and the next
+//! [`SimulcastShare::acquire`] builds a new hub.
+
+use crate::bus_monitor::{self, PipelineFailure};
+use crate::congestion::{self, BitrateController, CongestionConfig};
+use crate::encoder::{await_startup, Codec, EncoderControl, EncoderFailed, EncoderSettings, QualityProfile};
+use crate::frame_skip;
+use crate::input::InputTransform;
+use crate::keyframes::KeyframeLimiter;
+use crate::media_source;
+use crate::redaction::Redactor;
+use crate::screen_capture::{ScreenStreamer, StreamConfig};
+use crate::video_scaler::VideoScaler;
+use anyhow::Result;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app as gst_app;
+use std::sync::atomic::AtomicU32;
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::watch;
+
+/// Resolution scale and bitrate of each layer, best first
+const DEFAULT_LAYERS: &str = "1:4000,0.5:1200,0.25:400";
+
+/// One encoding of the shared capture
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayerConfig {
+    /// Output resolution relative to the full capture
+    pub scale: f64,
+    pub bitrate_kbps: u32,
+}
+
+impl LayerConfig {
+    /// Parse `SCALE:KBPS` pairs separated by commas, e.g. `1:4000,0.5:1200`,
+    /// sorted best first
+    pub fn parse_list(s: &str) -> Option<Vec<Self>> {
+        let mut layers = s
+            .split(',')
+            .map(|layer| {
+                let (scale, kbps) = layer.trim().split_once(':')?;
+                let scale: f64 = scale.trim().parse().ok()?;
+                let bitrate_kbps: u32 = kbps.trim().parse().ok()?;
+                (scale > 0.0 && scale <= 1.0 && bitrate_kbps > 0).then_some(Self { scale, bitrate_kbps })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        layers.sort_by(|a, b| b.bitrate_kbps.cmp(&a.bitrate_kbps));
+        (!layers.is_empty()).then_some(layers)
+    }
+
+    pub fn default_layers() -> Vec<Self> {
+        Self::parse_list(DEFAULT_LAYERS).unwrap()
+    }
+}
+
+/// Best layer whose bitrate fits `kbps`, or the lowest if none does
+fn layer_for_bitrate(layers: &[LayerConfig], kbps: u32) -> usize {
+    layers
+        .iter()
+        .position(|layer| layer.bitrate_kbps <= kbps)
+        .unwrap_or(layers.len() - 1)
+}
+
+/// One encoder branch of the hub
+struct Layer {
+    config: LayerConfig,
+    encoder: Arc<EncoderControl>,
+    keyframes: Option<Arc<KeyframeLimiter>>,
+    /// Keeps the branch's caps in step with the capture size
+    _scaler: Arc<VideoScaler>,
+    /// Maps the layer's video coordinates to screen coordinates
+    transform: Arc<Mutex<InputTransform>>,
+}
+
+/// Shared capture pipeline with one encoder per layer
+pub struct SimulcastHub {
+    pipeline: gst::Pipeline,
+    codec: Codec,
+    layers: Vec<Layer>,
+    /// Sessions receiving a layer
+    feeds: Mutex<Vec<Weak<SimulcastFeed>>>,
+    redactor: Arc<Redactor>,
+    /// Set once the pipeline's bus reports an error or EOS
+    failure: watch::Sender<Option<PipelineFailure>>,
+    /// Watches the pipeline's bus
+    bus_task: Mutex<Option<tokio::task::AbortHandle>>,
+}
+
+impl SimulcastHub {
+    /// Build the capture, a `tee` and an encoder branch per layer, each
+    /// ending in an `appsink` that hands frames to the feeds
+    fn new(config: &StreamConfig, layer_configs: &[LayerConfig]) -> Result<Arc<Self>> {
+        let fps = config.fps;
+        let pipeline = gst::Pipeline::with_name("simulcast");
+
+        let capture_src = match media_source::create_video_source(&config.source, fps)? {
+            Some(src) => src,
+            None => ScreenStreamer::create_screen_capture(fps, config.display.as_deref(), config.show_pointer())?,
+        };
+        let queue = gst::ElementFactory::make("queue")
+            .property("max-size-buffers", 3u32)
+            .property("max-size-time", 0u64)
+            .property("max-size-bytes", 0u32)
+            .build()?;
+        let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+        let tee = gst::ElementFactory::make("tee").build()?;
+
+        pipeline.add_many([&capture_src, &queue, &videoconvert, &tee])?;
+        gst::Element::link_many([&capture_src, &queue, &videoconvert, &tee])?;
+
+        // The shared capture always covers the whole screen
+        let redactor = Redactor::new(
+            config.redactions.clone(),
+            config.display.clone(),
+            Arc::new(Mutex::new(InputTransform::default())),
+        );
+        if let Some(pad) = tee.static_pad("sink") {
+            redactor.install(&pad);
+        }
+
+        if let (Some(idle_fps), Some(pad)) = (config.idle_fps, tee.static_pad("sink")) {
+            frame_skip::install(&pad, idle_fps);
+            if capture_src.has_property("use-damage", None) {
+                capture_src.set_property("use-damage", true);
+            }
+        }
+
+        // Feeds switch between layers mid-stream, so they all share a codec
+        let mut codecs = Codec::candidates(&config.codecs, None, &config.encoders);
+        let mut layers = Vec::new();
+        let mut appsinks = Vec::new();
+        for (index, layer) in layer_configs.iter().enumerate() {
+            let branch_queue = gst::ElementFactory::make("queue")
+                .property("max-size-buffers", 3u32)
+                .property_from_str("leaky", "downstream")
+                .build()?;
+            let videoscale = gst::ElementFactory::make("videoscale").build()?;
+            let videorate = gst::ElementFactory::make("videorate")
+                .property("drop-only", true)
+                .build()?;
+            let capsfilter = gst::ElementFactory::make("capsfilter").build()?;
+            let transform = Arc::new(Mutex::new(InputTransform::default()));
+            let scaler = VideoScaler::new(capsfilter.clone(), fps, config.max_resolution, transform.clone());
+            scaler.watch_source(&videoscale);
+            scaler.set_quality(1, layer.scale);
+
+            let settings = EncoderSettings {
+                bitrate: layer.bitrate_kbps,
+                fps,
+                keyframe_interval: config.keyframe_interval,
+                preset: config.preset,
+                profile: QualityProfile::Standard,
+                chroma_444: false,
+            };
+            let encoder = Arc::new(EncoderControl::create(&codecs, &config.encoders, settings)?);
+            codecs = vec![encoder.codec()];
+            let parser = encoder.codec().create_parser()?;
+            let appsink = gst_app::AppSink::builder()
+                .name(format!("layer{}", index))
+                .sync(false)
+                .build();
+
+            let mut chain = vec![&branch_queue, &videoscale, &videorate, &capsfilter, encoder.element()];
+            chain.extend(parser.as_ref());
+            chain.push(appsink.upcast_ref());
+            pipeline.add_many(chain.iter().copied())?;
+            gst::Element::link_many(chain.iter().copied())?;
+            tee.link(&branch_queue)?;
+
+            tracing::info!("Simulcast layer {}: scale {}, {} kbps", index, layer.scale, layer.bitrate_kbps);
+            layers.push(Layer {
+                config: *layer,
+                keyframes: KeyframeLimiter::install(encoder.element(), config.keyframe_min_interval),
+                encoder,
+                _scaler: scaler,
+                transform,
+            });
+            appsinks.push(appsink);
+        }
+
+        let hub = Arc::new(Self {
+            pipeline,
+            codec: codecs[0],
+            layers,
+            feeds: Mutex::new(Vec::new()),
+            redactor,
+            failure: watch::channel(None).0,
+            bus_task: Mutex::new(None),
+        });
+
+        for (index, appsink) in appsinks.iter().enumerate() {
+            let hub_weak = Arc::downgrade(&hub);
+            appsink.set_callbacks(
+                gst_app::AppSinkCallbacks::builder()
+                    .new_sample(move |appsink| {
+                        let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                        if let Some(hub) = hub_weak.upgrade() {
+                            hub.distribute(index, &sample);
+                        }
+                        Ok(gst::FlowSuccess::Ok)
+                    })
+                    .build(),
+            );
+        }
+
+        Ok(hub)
+    }
+
+    /// Build and start the hub, waiting for every layer's encoder
+    fn start(config: &StreamConfig, layer_configs: &[LayerConfig]) -> Result<Arc<Self>> {
+        let hub = Self::new(config, layer_configs)?;
+        let state_change = hub.pipeline.set_state(gst::State::Playing);
+        let encoders: Vec<&EncoderControl> = hub.layers.iter().map(|layer| &*layer.encoder).collect();
+        await_startup(&hub.pipeline, &encoders)?;
+        state_change?;
+        tracing::info!("Simulcast pipeline started ({})", hub.codec.encoding_name());
+        Ok(hub)
+    }
+
+    /// Watch the running pipeline's bus, recording its first failure
+    fn watch_bus(self: &Arc<Self>) {
+        let failed = bus_monitor::watch(&self.pipeline);
+        let hub = Arc::downgrade(self);
+        let task = tokio::spawn(async move {
+            let failure = failed.await;
+            if let Some(hub) = hub.upgrade() {
+                tracing::error!("Simulcast pipeline failed: {}", failure);
+                hub.failure.send_replace(Some(failure));
+            }
+        });
+        *self.bus_task.lock().unwrap() = Some(task.abort_handle());
+    }
+
+    /// Whether the pipeline has failed
+    pub fn is_failed(&self) -> bool {
+        self.failure.borrow().is_some()
+    }
+
+    /// Resolves with the pipeline's failure, once it fails
+    pub fn failed(&self) -> impl Future<Output = PipelineFailure> + Send + 'static {
+        let mut failure = self.failure.subscribe();
+        async move {
+            let failure = failure.wait_for(Option::is_some).await.ok().and_then(|f| f.clone());
+            match failure {
+                Some(failure) => failure,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Redaction of the shared capture
+    pub fn redactor(&self) -> Arc<Redactor> {
+        self.redactor.clone()
+    }
+
+    /// Whether a client announcing these codecs can decode the layers
+    pub fn supports(&self, client_codecs: Option<&[String]>) -> bool {
+        client_codecs.map_or(true, |names| {
+            names.iter().any(|n| n.eq_ignore_ascii_case(self.codec.encoding_name()))
+        })
+    }
+
+    /// Start feeding a session the layer that fits `start_kbps`
+    pub fn subscribe(self: &Arc<Self>, start_kbps: u32) -> Arc<SimulcastFeed> {
+        let appsrc = gst_app::AppSrc::builder()
+            .is_live(true)
+            .format(gst::Format::Time)
+            .do_timestamp(true)
+            .build();
+
+        let configs: Vec<_> = self.layers.iter().map(|l| l.config).collect();
+        let feed = Arc::new(SimulcastFeed {
+            hub: self.clone(),
+            appsrc,
+            state: Mutex::new(FeedState::default()),
+            transform: Arc::new(Mutex::new(InputTransform::default())),
+        });
+        feed.select_layer(layer_for_bitrate(&configs, start_kbps));
+
+        let mut feeds = self.feeds.lock().unwrap();
+        feeds.retain(|f| f.strong_count() > 0);
+        feeds.push(Arc::downgrade(&feed));
+        feed
+    }
+
+    /// Hand a layer's encoded frame to every live feed
+    fn distribute(&self, layer: usize, sample: &gst::Sample) {
+        let feeds: Vec<_> = self.feeds.lock().unwrap().iter().filter_map(Weak::upgrade).collect();
+        for feed in feeds {
+            feed.push(layer, sample);
+        }
+    }
+
+    fn request_keyframe(&self, layer: usize) {
+        if let Some(keyframes) = self.layers.get(layer).and_then(|l| l.keyframes.as_ref()) {
+            keyframes.request();
+        }
+    }
+}
+
+impl Drop for SimulcastHub {
+    fn drop(&mut self) {
+        if let Some(task) = self.bus_task.lock().unwrap().take() {
+            task.abort();
+        }
+        // The last reference may be dropped on one of our streaming threads,
+        // where stopping the pipeline would deadlock
+        let pipeline = self.pipeline.clone();
+        std::thread::spawn(move || {
+            let _ = pipeline.set_state(gst::State::Null);
+            tracing::info!("Simulcast pipeline stopped");
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+struct FeedState {
+    /// Layer being forwarded, once its first keyframe arrived
+    current: Option<usize>,
+    /// Layer to switch to at its next keyframe
+    target: usize,
+    /// Caps last set on the `appsrc`
+    caps: Option<gst::Caps>,
+}
+
+/// One session's view of the hub: the layer it forwards into its pipeline
+pub struct SimulcastFeed {
+    hub: Arc<SimulcastHub>,
+    appsrc: gst_app::AppSrc,
+    state: Mutex<FeedState>,
+    /// Input transform of the layer being forwarded
+    transform: Arc<Mutex<InputTransform>>,
+}
+
+impl SimulcastFeed {
+    /// Source element to put in the session's pipeline
+    pub fn appsrc(&self) -> &gst::Element {
+        self.appsrc.upcast_ref()
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.hub.codec
+    }
+
+    /// Maps the forwarded video's coordinates to screen coordinates, kept in
+    /// step with the current layer
+    pub fn input_transform(&self) -> Arc<Mutex<InputTransform>> {
+        self.transform.clone()
+    }
+
+    /// Resolves with the hub's failure, once it fails
+    pub fn hub_failed(&self) -> impl Future<Output = PipelineFailure> + Send + 'static {
+        self.hub.failed()
+    }
+
+    /// Switch to another layer at its next keyframe, asking for one
+    pub fn select_layer(&self, layer: usize) {
+        let layer = layer.min(self.hub.layers.len() - 1);
+        let mut state = self.state.lock().unwrap();
+        if state.target == layer && state.current.is_some() {
+            return;
+        }
+        state.target = layer;
+        drop(state);
+
+        self.hub.request_keyframe(layer);
+    }
+
+    /// Ask for a keyframe on the layer being forwarded
+    pub fn request_keyframe(&self) {
+        let state = self.state.lock().unwrap();
+        let layer = state.current.unwrap_or(state.target);
+        drop(state);
+        self.hub.request_keyframe(layer);
+    }
+
+    fn push(&self, layer: usize, sample: &gst::Sample) {
+        let Some(buffer) = sample.buffer() else { return };
+        let mut state = self.state.lock().unwrap();
+
+        if layer == state.target && state.current != Some(layer) {
+            if buffer.flags().contains(gst::BufferFlags::DELTA_UNIT) {
+                return;
+            }
+            let config = self.hub.layers[layer].config;
+            tracing::info!(
+                "Simulcast: switching to layer {} (scale {}, {} kbps)",
+                layer, config.scale, config.bitrate_kbps
+            );
+            state.current = Some(layer);
+        }
+        if state.current != Some(layer) {
+            return;
+        }
+
+        // Layers differ in resolution, so caps change with the layer
+        if let Some(caps) = sample.caps() {
+            if state.caps.as_ref().map(|c| c.as_ref()) != Some(caps) {
+                self.appsrc.set_caps(Some(&caps.to_owned()));
+                state.caps = Some(caps.to_owned());
+            }
+        }
+        drop(state);
+
+        // The layer's scale may change with the capture size, so follow it
+        // on every frame rather than only when switching
+        *self.transform.lock().unwrap() = *self.hub.layers[layer].transform.lock().unwrap();
+
+        // Restamp against this session's clock
+        let mut buffer = buffer.copy();
+        {
+            let buffer = buffer.make_mut();
+            buffer.set_pts(gst::ClockTime::NONE);
+            buffer.set_dts(gst::ClockTime::NONE);
+        }
+        let _ = self.appsrc.push_buffer(buffer);
+    }
+}
+
+/// Periodically pick the layer matching the session's bandwidth estimate
+pub fn spawn_layer_control(
+    config: &CongestionConfig,
+    webrtcbin: gst::Element,
+    feed: Arc<SimulcastFeed>,
+    estimate_bps: Option<Arc<AtomicU32>>,
+) -> tokio::task::AbortHandle {
+    let mut controller = BitrateController::new(config);
+    let layers: Vec<_> = feed.hub.layers.iter().map(|l| l.config).collect();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(congestion::SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(sample) = congestion::sample_network(&webrtcbin, estimate_bps.as_deref()).await else {
+                continue;
+            };
+            let target = controller.update(sample);
+            feed.select_layer(layer_for_bitrate(&layers, target.bitrate_kbps));
+        }
+    })
+    .abort_handle()
+}
+
+/// Starts the hub for the first viewer of the shared display and keeps it
+/// while any viewer remains
+pub struct SimulcastShare {
+    layers: Vec<LayerConfig>,
+    hub: tokio::sync::Mutex<Weak<SimulcastHub>>,
+}
+
+impl SimulcastShare {
+    pub fn new(layers: Vec<LayerConfig>) -> Self {
+        Self {
+            layers,
+            hub: tokio::sync::Mutex::new(Weak::new()),
+        }
+    }
+
+    /// The running hub, starting it (and falling back past failing
+    /// encoders) if there is none or it failed
+    pub async fn acquire(&self, config: &StreamConfig) -> Result<Arc<SimulcastHub>> {
+        let mut current = self.hub.lock().await;
+        if let Some(hub) = current.upgrade().filter(|hub| !hub.is_failed()) {
+            return Ok(hub);
+        }
+
+        let hub = loop {
+            let config = config.clone();
+            let layers = self.layers.clone();
+            match tokio::task::spawn_blocking(move || SimulcastHub::start(&config, &layers)).await? {
+                Ok(hub) => break hub,
+                Err(e) if e.is::<EncoderFailed>() => {
+                    tracing::warn!("{}, falling back to the next encoder", e);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        hub.watch_bus();
+        *current = Arc::downgrade(&hub);
+        Ok(hub)
+    }
+}
@@ -0,0 +1,214 @@
+//! Output resolution control
+//!
+//! The client reports its viewport and the scaled video is renegotiated to
+//! fit it, keeping the source aspect ratio, never upscaling, and staying
+//! within a configured maximum. Congestion control can further reduce frame
+//! rate and resolution. Input coordinates are remapped to match.
+
+use crate::input::InputTransform;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::sync::{Arc, Mutex};
+
+/// Inputs to the scaling decision
+#[derive(Debug)]
+struct ScaleState {
+    /// Configured frame rate
+    fps: u32,
+    /// Frame size entering `videoscale`
+    source: Option<(u32, u32)>,
+    /// Client viewport in device pixels
+    viewport: Option<(u32, u32)>,
+    /// Frame rate divisor requested by congestion control
+    fps_divisor: u32,
+    /// Resolution factor requested by congestion control
+    quality_scale: f64,
+    /// Size and frame rate currently set on the caps filter
+    applied: Option<(Option<(u32, u32)>, gst::Fraction)>,
+}
+
+impl Default for ScaleState {
+    fn default() -> Self {
+        Self {
+            fps: 30,
+            source: None,
+            viewport: None,
+            fps_divisor: 1,
+            quality_scale: 1.0,
+            applied: None,
+        }
+    }
+}
+
+/// Drives the caps filter after `videoscale`
+pub struct VideoScaler {
+    capsfilter: gst::Element,
+    max_size: Option<(u32, u32)>,
+    state: Mutex<ScaleState>,
+    input_transform: Arc<Mutex<InputTransform>>,
+}
+
+impl VideoScaler {
+    pub fn new(
+        capsfilter: gst::Element,
+        fps: u32,
+        max_size: Option<(u32, u32)>,
+        input_transform: Arc<Mutex<InputTransform>>,
+    ) -> Arc<Self> {
+        let scaler = Arc::new(Self {
+            capsfilter,
+            max_size,
+            state: Mutex::new(ScaleState {
+                fps,
+                ..ScaleState::default()
+            }),
+            input_transform,
+        });
+        scaler.apply(&mut scaler.state.lock().unwrap());
+        scaler
+    }
+
+    /// Configured maximum output size
+    pub fn max_size(&self) -> Option<(u32, u32)> {
+        self.max_size
+    }
+
+    /// Track the source frame size from caps events on `videoscale`'s sink pad
+    pub fn watch_source(self: &Arc<Self>, videoscale: &gst::Element) {
+        let Some(pad) = videoscale.static_pad("sink") else { return };
+        let scaler = Arc::downgrade(self);
+
+        pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+            if let Some(gst::PadProbeData::Event(event)) = &info.data {
+                if let gst::EventView::Caps(caps) = event.view() {
+                    let size = caps.caps().structure(0).and_then(|s| {
+                        Some((s.get::<i32>("width").ok()? as u32, s.get::<i32>("height").ok()? as u32))
+                    });
+                    if let (Some(scaler), Some(size)) = (scaler.upgrade(), size) {
+                        scaler.set_source_size(size);
+                    }
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    fn set_source_size(&self, size: (u32, u32)) {
+        let mut state = self.state.lock().unwrap();
+        if state.source != Some(size) {
+            state.source = Some(size);
+            self.apply(&mut state);
+        }
+    }
+
+    /// Fit the output to a new client viewport
+    pub fn set_viewport(&self, width: u32, height: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.viewport = Some((width.max(1), height.max(1)));
+        self.apply(&mut state);
+    }
+
+    /// Change the configured frame rate
+    pub fn set_fps(&self, fps: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.fps = fps.max(1);
+        self.apply(&mut state);
+    }
+
+    /// Reduce frame rate and resolution below what the viewport allows
+    pub fn set_quality(&self, fps_divisor: u32, scale: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.fps_divisor = fps_divisor.max(1);
+        state.quality_scale = scale.clamp(0.1, 1.0);
+        self.apply(&mut state);
+    }
+
+    /// Recompute the output caps and renegotiate if they changed
+    fn apply(&self, state: &mut ScaleState) {
+        let output = state.source.map(|source| {
+            let (width, height) = fit_resolution(source, state.viewport, self.max_size);
+            let even = |v: u32| (((v as f64 * state.quality_scale) as u32) & !1).max(2);
+            (even(width), even(height))
+        });
+
+        let mut transform = self.input_transform.lock().unwrap();
+        match (state.source, output) {
+            (Some(source), Some(output)) => {
+                transform.scale_x = source.0 as f64 / output.0 as f64;
+                transform.scale_y = source.1 as f64 / output.1 as f64;
+            }
+            _ => {
+                transform.scale_x = 1.0;
+                transform.scale_y = 1.0;
+            }
+        }
+        drop(transform);
+
+        let framerate = gst::Fraction::new(state.fps as i32, state.fps_divisor as i32);
+        if state.applied == Some((output, framerate)) {
+            return;
+        }
+        state.applied = Some((output, framerate));
+
+        let mut caps = gst::Caps::builder("video/x-raw").field("framerate", framerate);
+        if let Some((width, height)) = output {
+            caps = caps
+                .field("width", width as i32)
+                .field("height", height as i32)
+                .field("pixel-aspect-ratio", gst::Fraction::new(1, 1));
+            tracing::info!("Scaling video to {}x{}", width, height);
+        }
+        self.capsfilter.set_property("caps", caps.build());
+    }
+}
+
+/// Largest size with the source aspect ratio that fits the viewport and
+/// maximum without upscaling. Dimensions are rounded down to even numbers
+/// as most encoders require.
+pub fn fit_resolution(
+    source: (u32, u32),
+    viewport: Option<(u32, u32)>,
+    max_size: Option<(u32, u32)>,
+) -> (u32, u32) {
+    let (sw, sh) = (source.0.max(1) as f64, source.1.max(1) as f64);
+
+    let mut scale: f64 = 1.0;
+    for (bw, bh) in [viewport, max_size].into_iter().flatten() {
+        scale = scale.min(bw as f64 / sw).min(bh as f64 / sh);
+    }
+
+    let even = |v: f64| ((v.floor() as u32) & !1).max(2);
+    (even(sw * scale), even(sh * scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_viewport_keeping_aspect() {
+        assert_eq!(fit_resolution((1920, 1080), Some((1280, 1024)), None), (1280, 720));
+        assert_eq!(fit_resolution((1920, 1080), Some((1920, 540)), None), (960, 540));
+    }
+
+    #[test]
+    fn never_upscales() {
+        assert_eq!(fit_resolution((1920, 1080), Some((3840, 2160)), None), (1920, 1080));
+        assert_eq!(fit_resolution((1280, 720), None, None), (1280, 720));
+    }
+
+    #[test]
+    fn caps_at_max_resolution() {
+        assert_eq!(fit_resolution((3840, 2160), None, Some((1920, 1080))), (1920, 1080));
+        assert_eq!(fit_resolution((3840, 2160), Some((2560, 1600)), Some((1280, 720))), (1280, 720));
+        assert_eq!(fit_resolution((3840, 2160), Some((640, 480)), Some((1920, 1080))), (640, 360));
+    }
+
+    #[test]
+    fn rounds_down_to_even() {
+        assert_eq!(fit_resolution((1921, 1081), None, None), (1920, 1080));
+        assert_eq!(fit_resolution((1366, 768), Some((683, 1000)), None), (682, 384));
+        assert_eq!(fit_resolution((1920, 1080), Some((1, 1)), None), (2, 2));
+        assert_eq!(fit_resolution((0, 0), None, None), (2, 2));
+    }
+}
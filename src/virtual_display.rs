@@ -0,0 +1,321 @@
+//! Headless virtual display management
+//!
+//! Spawns and supervises an Xvfb (or Xvnc) server, plus an optional window
+//! manager, so streamio can run on machines without a physical display.
+
+use crate::config::{env_parse, env_string, parse_resolution};
+use crate::process::{detached, terminate};
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const DEFAULT_SERVER: &str = "Xvfb";
+const DEFAULT_RESOLUTION: (u32, u32) = (1920, 1080);
+const DEFAULT_DEPTH: u32 = 24;
+
+/// First display number tried when none is configured
+const FIRST_DISPLAY: u32 = 99;
+const MAX_DISPLAY: u32 = 999;
+
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Display numbers handed out by this process and not yet released
+static RESERVED_DISPLAYS: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
+/// Virtual display settings
+#[derive(Debug, Clone)]
+pub struct VirtualDisplayConfig {
+    /// X server binary (`Xvfb`, `Xvnc`, or a full path)
+    pub server: String,
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+    /// Fixed display number; a free one is picked when unset
+    pub display: Option<u32>,
+    /// Shell command for the window manager, started once the server is up
+    pub window_manager: Option<String>,
+}
+
+impl Default for VirtualDisplayConfig {
+    fn default() -> Self {
+        Self {
+            server: DEFAULT_SERVER.to_string(),
+            width: DEFAULT_RESOLUTION.0,
+            height: DEFAULT_RESOLUTION.1,
+            depth: DEFAULT_DEPTH,
+            display: None,
+            window_manager: None,
+        }
+    }
+}
+
+impl VirtualDisplayConfig {
+    /// Read `HEADLESS_*` settings from the environment
+    pub fn from_env() -> Self {
+        let (width, height) = env_string("HEADLESS_RESOLUTION")
+            .and_then(|s| parse_resolution(&s))
+            .unwrap_or(DEFAULT_RESOLUTION);
+
+        Self {
+            server: env_string("HEADLESS_SERVER").unwrap_or_else(|| DEFAULT_SERVER.to_string()),
+            width,
+            height,
+            depth: env_parse("HEADLESS_DEPTH").unwrap_or(DEFAULT_DEPTH),
+            display: env_string("HEADLESS_DISPLAY")
+                .and_then(|s| s.trim_start_matches(':').parse().ok()),
+            window_manager: env_string("HEADLESS_WM"),
+        }
+    }
+
+    /// Whether the configured server takes Xvnc-style arguments
+    fn is_xvnc(&self) -> bool {
+        Path::new(&self.server)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with("Xvnc"))
+    }
+
+    fn server_command(&self, number: u32) -> Command {
+        let mut cmd = detached(&self.server);
+        cmd.arg(format!(":{}", number));
+
+        if self.is_xvnc() {
+            cmd.args(["-geometry", &format!("{}x{}", self.width, self.height)])
+                .args(["-depth", &self.depth.to_string()])
+                .args(["-SecurityTypes", "None"]);
+        } else {
+            cmd.args(["-screen", "0", &format!("{}x{}x{}", self.width, self.height, self.depth)]);
+        }
+
+        cmd.args(["-nolisten", "tcp", "-noreset"]);
+        cmd
+    }
+}
+
+/// Processes owned by a virtual display
+struct Processes {
+    server: Child,
+    window_manager: Option<Child>,
+}
+
+/// A supervised X server, terminated when dropped
+pub struct VirtualDisplay {
+    number: u32,
+    name: String,
+    processes: Arc<Mutex<Processes>>,
+    stop: Arc<AtomicBool>,
+    supervisor: Option<JoinHandle<()>>,
+}
+
+impl VirtualDisplay {
+    /// Start the X server and window manager, and supervise them until dropped
+    pub fn spawn(config: &VirtualDisplayConfig) -> Result<Self> {
+        let number = reserve_display(config.display)?;
+        let name = format!(":{}", number);
+
+        let processes = match start_processes(config, number) {
+            Ok(p) => p,
+            Err(e) => {
+                release_display(number);
+                return Err(e);
+            }
+        };
+
+        tracing::info!(
+            "Virtual display {} started ({}x{}x{} via {})",
+            name, config.width, config.height, config.depth, config.server
+        );
+
+        let processes = Arc::new(Mutex::new(processes));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let supervisor = {
+            let config = config.clone();
+            let processes = processes.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || supervise(config, number, processes, stop))
+        };
+
+        Ok(Self {
+            number,
+            name,
+            processes,
+            stop,
+            supervisor: Some(supervisor),
+        })
+    }
+
+    /// X display name, e.g. `:99`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for VirtualDisplay {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.supervisor.take() {
+            let _ = handle.join();
+        }
+
+        let mut processes = self.processes.lock().unwrap();
+        if let Some(wm) = processes.window_manager.as_mut() {
+            terminate(wm);
+        }
+        terminate(&mut processes.server);
+        drop(processes);
+
+        release_display(self.number);
+        tracing::info!("Virtual display {} stopped", self.name);
+    }
+}
+
+/// Restart the server or window manager whenever one of them exits
+fn supervise(
+    config: VirtualDisplayConfig,
+    number: u32,
+    processes: Arc<Mutex<Processes>>,
+    stop: Arc<AtomicBool>,
+) {
+    let name = format!(":{}", number);
+
+    while !stop.load(Ordering::SeqCst) {
+        std::thread::sleep(SUPERVISE_INTERVAL);
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let mut p = processes.lock().unwrap();
+
+        if let Ok(Some(status)) = p.server.try_wait() {
+            tracing::warn!("X server on {} exited ({}), restarting", name, status);
+            if let Some(wm) = p.window_manager.as_mut() {
+                terminate(wm);
+            }
+            match start_processes(&config, number) {
+                Ok(restarted) => *p = restarted,
+                Err(e) => tracing::error!("Failed to restart virtual display {}: {}", name, e),
+            }
+            continue;
+        }
+
+        let wm_exited = match p.window_manager.as_mut() {
+            Some(wm) => matches!(wm.try_wait(), Ok(Some(_))),
+            None => false,
+        };
+        if wm_exited {
+            tracing::warn!("Window manager on {} exited, restarting", name);
+            if let Some(cmd) = &config.window_manager {
+                match start_window_manager(cmd, &name) {
+                    Ok(wm) => p.window_manager = Some(wm),
+                    Err(e) => tracing::error!("Failed to restart window manager on {}: {}", name, e),
+                }
+            }
+        }
+    }
+}
+
+fn start_processes(config: &VirtualDisplayConfig, number: u32) -> Result<Processes> {
+    let name = format!(":{}", number);
+
+    let mut server = config
+        .server_command(number)
+        .spawn()
+        .with_context(|| format!("Failed to start '{}' — is it installed?", config.server))?;
+
+    if let Err(e) = wait_until_ready(number, &mut server) {
+        terminate(&mut server);
+        return Err(e);
+    }
+
+    let window_manager = match &config.window_manager {
+        Some(cmd) => match start_window_manager(cmd, &name) {
+            Ok(wm) => Some(wm),
+            Err(e) => {
+                terminate(&mut server);
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+
+    Ok(Processes { server, window_manager })
+}
+
+fn start_window_manager(cmd: &str, display_name: &str) -> Result<Child> {
+    let child = detached("sh")
+        .args(["-c", cmd])
+        .env("DISPLAY", display_name)
+        .spawn()
+        .with_context(|| format!("Failed to start window manager '{}'", cmd))?;
+
+    tracing::info!("Window manager '{}' started on {}", cmd, display_name);
+    Ok(child)
+}
+
+/// Wait for the server's X11 socket to appear
+fn wait_until_ready(number: u32, server: &mut Child) -> Result<()> {
+    let socket = socket_path(number);
+    let deadline = Instant::now() + READY_TIMEOUT;
+
+    while Instant::now() < deadline {
+        if let Some(status) = server.try_wait()? {
+            anyhow::bail!("X server on :{} exited during startup ({})", number, status);
+        }
+        if Path::new(&socket).exists() {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    anyhow::bail!("X server on :{} did not become ready within {:?}", number, READY_TIMEOUT)
+}
+
+fn socket_path(number: u32) -> String {
+    format!("/tmp/.X11-unix/X{}", number)
+}
+
+/// Whether another X server already owns this display number
+fn display_in_use(number: u32) -> bool {
+    if Path::new(&socket_path(number)).exists() {
+        return true;
+    }
+
+    // A lock file only counts if its owner is still alive
+    let lock = format!("/tmp/.X{}-lock", number);
+    match std::fs::read_to_string(&lock) {
+        Ok(contents) => match contents.trim().parse::<u32>() {
+            Ok(pid) => Path::new(&format!("/proc/{}", pid)).exists(),
+            Err(_) => true,
+        },
+        Err(_) => false,
+    }
+}
+
+fn reserve_display(requested: Option<u32>) -> Result<u32> {
+    let mut reserved = RESERVED_DISPLAYS.lock().unwrap();
+
+    if let Some(number) = requested {
+        if reserved.contains(&number) || display_in_use(number) {
+            anyhow::bail!("Display :{} is already in use", number);
+        }
+        reserved.insert(number);
+        return Ok(number);
+    }
+
+    let number = (FIRST_DISPLAY..=MAX_DISPLAY)
+        .find(|n| !reserved.contains(n) && !display_in_use(*n))
+        .context("No free X display number available")?;
+    reserved.insert(number);
+    Ok(number)
+}
+
+fn release_display(number: u32) {
+    RESERVED_DISPLAYS.lock().unwrap().remove(&number);
+}
@@ -0,0 +1,238 @@
+//! Forensic watermark
+//!
+//! With `WATERMARK=1` the viewer's identity, the session id and the current
+//! time are burnt into the video before encoding, so a leaked screenshot or
+//! recording can be traced back to the session it came from. Each session
+//! encodes its own watermarked stream.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::glib;
+use gstreamer::prelude::*;
+use std::time::Duration;
+
+const DEFAULT_TEMPLATE: &str = "{user} · session {session} · {time}";
+/// Font size of the tiled pattern, in pixels of the output frame
+const TILE_FONT_PX: f64 = 20.0;
+/// Spaces between copies of the text in a row
+const TILE_GAP: &str = "        ";
+/// Frame size assumed until caps are negotiated
+const DEFAULT_FRAME: (u32, u32) = (1920, 1080);
+
+/// Where the watermark is drawn
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+    /// Repeated across the whole picture
+    Tiled,
+}
+
+impl std::str::FromStr for WatermarkPosition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "top-left" => Ok(Self::TopLeft),
+            "top-right" => Ok(Self::TopRight),
+            "bottom-left" => Ok(Self::BottomLeft),
+            "bottom-right" => Ok(Self::BottomRight),
+            "center" => Ok(Self::Center),
+            "tiled" => Ok(Self::Tiled),
+            other => anyhow::bail!(
+                "Unknown watermark position '{}' (expected top-left, top-right, bottom-left, bottom-right, center or tiled)",
+                other
+            ),
+        }
+    }
+}
+
+impl WatermarkPosition {
+    /// `textoverlay` horizontal and vertical alignment
+    fn alignment(self) -> (&'static str, &'static str) {
+        match self {
+            Self::TopLeft => ("left", "top"),
+            Self::TopRight => ("right", "top"),
+            Self::BottomLeft => ("left", "bottom"),
+            Self::BottomRight => ("right", "bottom"),
+            Self::Center | Self::Tiled => ("center", "center"),
+        }
+    }
+}
+
+/// Who a session's stream is for
+#[derive(Debug, Clone, Default)]
+pub struct Viewer {
+    pub user: Option<String>,
+    pub session: u64,
+}
+
+/// Watermark settings shared by all sessions
+#[derive(Debug, Clone)]
+pub struct WatermarkConfig {
+    /// Text with `{user}`, `{session}` and `{time}` placeholders
+    pub template: String,
+    pub position: WatermarkPosition,
+    /// 0 (invisible) to 1 (opaque)
+    pub opacity: f64,
+}
+
+impl WatermarkConfig {
+    /// Read `WATERMARK`, `WATERMARK_TEMPLATE`, `WATERMARK_POSITION` and
+    /// `WATERMARK_OPACITY`; `None` unless enabled
+    pub fn from_env() -> Result<Option<Self>> {
+        if !crate::config::env_flag("WATERMARK") {
+            return Ok(None);
+        }
+        let position = match crate::config::env_string("WATERMARK_POSITION") {
+            Some(s) => s.parse()?,
+            None => WatermarkPosition::default(),
+        };
+        let opacity = match crate::config::env_string("WATERMARK_OPACITY") {
+            Some(s) => s.parse::<f64>().context("Invalid WATERMARK_OPACITY")?,
+            None => 0.3,
+        };
+        anyhow::ensure!((0.0..=1.0).contains(&opacity), "WATERMARK_OPACITY must be between 0 and 1");
+
+        let config = Self {
+            template: crate::config::env_string("WATERMARK_TEMPLATE").unwrap_or_else(|| DEFAULT_TEMPLATE.to_string()),
+            position,
+            opacity,
+        };
+        tracing::info!("Watermarking sessions with '{}' ({:?})", config.template, config.position);
+        Ok(Some(config))
+    }
+
+    /// Watermark text for `viewer` at the current time, tiled to fill `frame` if requested
+    fn render(&self, viewer: &Viewer, frame: Option<(u32, u32)>) -> String {
+        let time = glib::DateTime::now_utc()
+            .and_then(|now| now.format("%Y-%m-%d %H:%M:%S UTC"))
+            .map(|s| s.to_string())
+            .unwrap_or_default();
+        let text = self
+            .template
+            .replace("{user}", viewer.user.as_deref().unwrap_or("anonymous"))
+            .replace("{session}", &viewer.session.to_string())
+            .replace("{time}", &time);
+
+        if self.position == WatermarkPosition::Tiled {
+            let (width, height) = frame.unwrap_or(DEFAULT_FRAME);
+            let (columns, rows) = tile_grid(text.chars().count(), width, height);
+            let row = vec![text; columns].join(TILE_GAP);
+            vec![row; rows].join("\n\n")
+        } else {
+            text
+        }
+    }
+
+    /// `textoverlay` drawing the watermark for `viewer`
+    pub fn create_overlay(&self, viewer: &Viewer) -> Result<gst::Element> {
+        let alpha = (self.opacity * 255.0).round() as u32;
+        let (halignment, valignment) = self.position.alignment();
+        let tiled = self.position == WatermarkPosition::Tiled;
+        // The tiled pattern is sized in frame pixels, so the font must not scale with the frame
+        let font = if tiled { format!("Sans {}px", TILE_FONT_PX) } else { "Sans 11".to_string() };
+
+        let overlay = gst::ElementFactory::make("textoverlay")
+            .name("watermark")
+            .property("text", self.render(viewer, None))
+            .property("font-desc", font)
+            .property("auto-resize", !tiled)
+            .property_from_str("wrap-mode", if tiled { "none" } else { "wordchar" })
+            .property_from_str("halignment", halignment)
+            .property_from_str("valignment", valignment)
+            .property_from_str("line-alignment", "center")
+            .property("color", (alpha << 24) | 0x00ff_ffff)
+            .property("draw-outline", true)
+            .property("outline-color", alpha << 24)
+            .property("draw-shadow", false)
+            .property("shaded-background", false)
+            .build()
+            .context("Watermark needs the textoverlay element (GStreamer pango plugin)")?;
+
+        // Re-tile for the negotiated resolution
+        if tiled {
+            let pad = overlay.static_pad("video_sink").context("textoverlay has no video_sink pad")?;
+            let config = self.clone();
+            let viewer = viewer.clone();
+            let weak = overlay.downgrade();
+            pad.connect_notify(Some("caps"), move |_, _| {
+                if let Some(overlay) = weak.upgrade() {
+                    overlay.set_property("text", config.render(&viewer, frame_size(&overlay)));
+                }
+            });
+        }
+        Ok(overlay)
+    }
+
+    /// Keep the overlay's time current, if the template shows it
+    pub fn spawn_clock(&self, overlay: &gst::Element, viewer: Viewer) -> Option<tokio::task::AbortHandle> {
+        if !self.template.contains("{time}") {
+            return None;
+        }
+        let config = self.clone();
+        let overlay = overlay.downgrade();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                let Some(overlay) = overlay.upgrade() else { break };
+                overlay.set_property("text", config.render(&viewer, frame_size(&overlay)));
+            }
+        });
+        Some(task.abort_handle())
+    }
+}
+
+/// Width and height of the frames entering `overlay`, once negotiated
+fn frame_size(overlay: &gst::Element) -> Option<(u32, u32)> {
+    let caps = overlay.static_pad("video_sink")?.current_caps()?;
+    let structure = caps.structure(0)?;
+    let width = structure.get::<i32>("width").ok()?;
+    let height = structure.get::<i32>("height").ok()?;
+    Some((width.max(1) as u32, height.max(1) as u32))
+}
+
+/// Copies per row and rows of a `chars`-long text that fit a `width`×`height`
+/// frame at [`TILE_FONT_PX`], estimating Sans glyphs as 0.6 em wide and lines
+/// as 1.2 em tall. Rows are separated by a blank line.
+fn tile_grid(chars: usize, width: u32, height: u32) -> (usize, usize) {
+    let char_width = TILE_FONT_PX * 0.6;
+    let line_height = TILE_FONT_PX * 1.2;
+    let text_width = chars.max(1) as f64 * char_width;
+    let gap_width = TILE_GAP.len() as f64 * char_width;
+
+    // n copies take n * text + (n - 1) * gap; n rows take (2n - 1) lines
+    let columns = ((width as f64 + gap_width) / (text_width + gap_width)).floor() as usize;
+    let rows = ((height as f64 + line_height) / (2.0 * line_height)).floor() as usize;
+    (columns.max(1), rows.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiling_follows_resolution() {
+        let text = "alice · session 12 · 2024-01-01 12:00:00 UTC".chars().count();
+        let (hd_columns, hd_rows) = tile_grid(text, 1280, 720);
+        let (uhd_columns, uhd_rows) = tile_grid(text, 3840, 2160);
+        assert!(uhd_columns > hd_columns);
+        assert!(uhd_rows > hd_rows);
+
+        // The block fits the frame
+        let width = TILE_FONT_PX * 0.6 * (uhd_columns * text + (uhd_columns - 1) * TILE_GAP.len()) as f64;
+        let height = TILE_FONT_PX * 1.2 * (2 * uhd_rows - 1) as f64;
+        assert!(width <= 3840.0 && height <= 2160.0);
+    }
+
+    #[test]
+    fn tiny_frames_get_one_copy() {
+        assert_eq!(tile_grid(40, 16, 16), (1, 1));
+        assert_eq!(tile_grid(0, 0, 0), (1, 1));
+    }
+}
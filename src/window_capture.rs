@@ -0,0 +1,216 @@
+//! X11 window lookup for single-window capture
+//!
+//! Windows are found with `xwininfo -root -tree`, by XID, title or WM_CLASS,
+//! and looked up again periodically so capture can follow them as they move,
+//! resize or get recreated.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+/// A window on an X screen
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowInfo {
+    pub id: u64,
+    pub title: Option<String>,
+    /// WM_CLASS instance name
+    pub instance: Option<String>,
+    /// WM_CLASS class name
+    pub class: Option<String>,
+    /// Absolute screen position
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How to find the window to capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowSelector {
+    /// A specific XID; not re-resolved if the window is recreated
+    Id(u64),
+    /// First window whose title contains the text
+    Title(String),
+    /// First window whose WM_CLASS instance or class matches (case-insensitive)
+    Class(String),
+}
+
+impl WindowSelector {
+    /// Parse `id:0x1e00007`, `title:Firefox`, `class:firefox`, or a bare
+    /// hex XID / title
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return None;
+        }
+
+        if let Some(id) = s.strip_prefix("id:") {
+            return parse_xid(id).map(Self::Id);
+        }
+        if let Some(title) = s.strip_prefix("title:") {
+            return Some(Self::Title(title.to_string()));
+        }
+        if let Some(class) = s.strip_prefix("class:") {
+            return Some(Self::Class(class.to_string()));
+        }
+
+        Some(parse_xid(s).map_or_else(|| Self::Title(s.to_string()), Self::Id))
+    }
+
+    pub fn matches(&self, window: &WindowInfo) -> bool {
+        match self {
+            Self::Id(id) => window.id == *id,
+            Self::Title(title) => window.title.as_deref().is_some_and(|t| t.contains(title.as_str())),
+            Self::Class(class) => [&window.instance, &window.class]
+                .into_iter()
+                .flatten()
+                .any(|c| c.eq_ignore_ascii_case(class)),
+        }
+    }
+
+    /// Find the first mapped-size window matching the selector
+    pub fn resolve(&self, windows: &[WindowInfo]) -> Option<WindowInfo> {
+        windows
+            .iter()
+            .find(|w| w.width > 1 && w.height > 1 && self.matches(w))
+            .cloned()
+    }
+}
+
+fn parse_xid(s: &str) -> Option<u64> {
+    let hex = s.trim().strip_prefix("0x")?;
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// List all windows of an X display (`$DISPLAY` when `None`)
+pub fn list_windows(display: Option<&str>) -> Result<Vec<WindowInfo>> {
+    let mut cmd = Command::new("xwininfo");
+    cmd.args(["-root", "-tree"]);
+    if let Some(display) = display {
+        cmd.env("DISPLAY", display);
+    }
+
+    let output = cmd.output().context("Failed to run xwininfo — is it installed?")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "xwininfo failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(parse_tree_line)
+        .collect())
+}
+
+/// Parse one window line of `xwininfo -root -tree`:
+///
+/// ```text
+///      0x1e00007 "Terminal": ("xterm" "XTerm")  484x316+0+0  +10+30
+///      0x1c00001 (has no name): ()  1x1+-1+-1  +-1+-1
+/// ```
+fn parse_tree_line(line: &str) -> Option<WindowInfo> {
+    let line = line.trim();
+    let (id, rest) = line.split_once(' ')?;
+    let id = parse_xid(id)?;
+
+    // Geometry and absolute position are the last two fields
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let [.., geometry, absolute] = fields[..] else { return None };
+    let names = rest[..rest.rfind(geometry)?].trim();
+
+    let (width, height) = crate::config::parse_resolution(geometry.split('+').next()?)?;
+    let (x, y) = parse_position(absolute)?;
+
+    // `"title": ("instance" "class")`, the class part may be `()`
+    let (title_part, class_part) = match names.rfind(": (") {
+        Some(i) => (&names[..i], &names[i + 2..]),
+        None => (names.trim_end_matches(':'), ""),
+    };
+    let title = title_part
+        .strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .map(str::to_string);
+    let mut class_names = class_part.split('"').skip(1).step_by(2).map(str::to_string);
+
+    Some(WindowInfo {
+        id,
+        title,
+        instance: class_names.next(),
+        class: class_names.next(),
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
+/// Parse `+X+Y`, where negative values are written as `+-X`
+fn parse_position(s: &str) -> Option<(i32, i32)> {
+    let (x, y) = s.strip_prefix('+')?.split_once('+')?;
+    Some((x.parse().ok()?, y.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_named_window() {
+        let window = parse_tree_line("     0x1e00007 \"Terminal\": (\"xterm\" \"XTerm\")  484x316+0+0  +10+30").unwrap();
+        assert_eq!(
+            window,
+            WindowInfo {
+                id: 0x1e00007,
+                title: Some("Terminal".into()),
+                instance: Some("xterm".into()),
+                class: Some("XTerm".into()),
+                x: 10,
+                y: 30,
+                width: 484,
+                height: 316,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_unnamed_window() {
+        let window = parse_tree_line("     0x1c00001 (has no name): ()  1x1+-1+-1  +-1+-1").unwrap();
+        assert_eq!(
+            window,
+            WindowInfo {
+                id: 0x1c00001,
+                title: None,
+                instance: None,
+                class: None,
+                x: -1,
+                y: -1,
+                width: 1,
+                height: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn title_may_contain_class_separator() {
+        let line = "  0x2a00003 \"Re: (draft): (notes)\": (\"thunderbird\" \"Thunderbird\")  800x600+0+0  +100+50";
+        let window = parse_tree_line(line).unwrap();
+        assert_eq!(window.title.as_deref(), Some("Re: (draft): (notes)"));
+        assert_eq!(window.instance.as_deref(), Some("thunderbird"));
+        assert_eq!(window.class.as_deref(), Some("Thunderbird"));
+        assert_eq!((window.x, window.y, window.width, window.height), (100, 50, 800, 600));
+    }
+
+    #[test]
+    fn skips_non_window_lines() {
+        for line in [
+            "",
+            "xwininfo: Window id: 0x7b7 (the root window) (has no name)",
+            "  Root window id: 0x7b7 (the root window) (has no name)",
+            "     2 children:",
+        ] {
+            assert_eq!(parse_tree_line(line), None, "{:?}", line);
+        }
+    }
+}